@@ -0,0 +1,63 @@
+(* File: bench.ml *)
+
+(* Build a synthetic vocabulary the size of a real encoding: every single byte, every byte pair,
+   and every three-letter lowercase word *)
+let synthetic_encoder () =
+  let encoder = ref [] in
+  let rank = ref 0 in
+  let add s =
+    encoder := (Bytes.of_string s, !rank) :: !encoder;
+    incr rank
+  in
+  for a = 0 to 255 do
+    add (String.make 1 (Char.chr a))
+  done;
+  for a = 0 to 255 do
+    for b = 0 to 255 do
+      add (String.init 2 (fun i -> Char.chr (if i = 0 then a else b)))
+    done
+  done;
+  for a = 0 to 25 do
+    for b = 0 to 25 do
+      for c = 0 to 25 do
+        add (String.init 3 (fun i -> Char.chr (97 + List.nth [a; b; c] i)))
+      done
+    done
+  done;
+  !encoder
+
+let time name iterations f =
+  let start = Sys.time () in
+  for _ = 1 to iterations do
+    ignore (Sys.opaque_identity (f ()))
+  done;
+  let elapsed = Sys.time () -. start in
+  Printf.printf "%-40s %10.2f us/call\n" name (elapsed *. 1e6 /. float_of_int iterations)
+
+let () =
+  let encoder = synthetic_encoder () in
+  let special_tokens = [("<|endoftext|>", List.length encoder)] in
+  let pattern = "\\w+| ?[^\\s\\w]+|\\s+" in
+  let id = Result.get_ok (Ocaml_rust_tiktok.core_bpe_new encoder special_tokens pattern) in
+  let enc = Result.get_ok (Ocaml_rust_tiktok.encoding_new encoder special_tokens pattern) in
+  Printf.printf "Vocabulary size: %d\n" (List.length encoder);
+
+  (* Short inputs are dominated by per-call overhead: looking an instance up in the store must
+     not copy the vocabulary *)
+  let text = "hello world" in
+  let iterations = 10_000 in
+  time "core_bpe_encode_ordinary (store ID)" iterations (fun () ->
+      Ocaml_rust_tiktok.core_bpe_encode_ordinary id text);
+  time "encoding_encode_ordinary (handle)" iterations (fun () ->
+      Ocaml_rust_tiktok.encoding_encode_ordinary enc text);
+
+  let tokens = Result.get_ok (Ocaml_rust_tiktok.encoding_encode_ordinary enc text) in
+  time "core_bpe_decode_bytes (store ID)" iterations (fun () ->
+      Ocaml_rust_tiktok.core_bpe_decode_bytes id tokens);
+  time "encoding_decode_bytes (handle)" iterations (fun () ->
+      Ocaml_rust_tiktok.encoding_decode_bytes enc tokens);
+
+  (* A single regex piece tens of kilobytes long, like a base64 blob or a run of digits *)
+  let long_piece = String.init 50_000 (fun i -> Char.chr (97 + (i * 7 mod 26))) in
+  time "encoding_encode_ordinary (50KB piece)" 10 (fun () ->
+      Ocaml_rust_tiktok.encoding_encode_ordinary enc long_piece)
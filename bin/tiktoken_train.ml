@@ -0,0 +1,41 @@
+(* File: tiktoken_train.ml *)
+
+(* Learns a BPE vocabulary from corpus files and writes it as a .tiktoken rank file that
+   encoding_new_from_file can load with the same pattern. Each file is one document. *)
+
+let usage = "tiktoken-train [-pattern REGEX | -encoding NAME] -vocab-size N -o OUTPUT CORPUS..."
+
+let read_file path =
+  let ic = open_in_bin path in
+  Fun.protect ~finally:(fun () -> close_in ic) (fun () -> really_input_string ic (in_channel_length ic))
+
+let () =
+  let pattern = ref None in
+  let encoding = ref "cl100k_base" in
+  let vocab_size = ref 0 in
+  let output = ref "" in
+  let corpus = ref [] in
+  let spec = [
+    ("-pattern", Arg.String (fun p -> pattern := Some p), "REGEX  Pre-tokenization regex");
+    ("-encoding", Arg.Set_string encoding, "NAME  Use the regex of a standard encoding (default cl100k_base)");
+    ("-vocab-size", Arg.Set_int vocab_size, "N  Number of tokens to learn, including the 256 bytes");
+    ("-o", Arg.Set_string output, "OUTPUT  Path of the .tiktoken file to write");
+  ] in
+  Arg.parse spec (fun path -> corpus := path :: !corpus) usage;
+  if !output = "" || !corpus = [] then (Arg.usage spec usage; exit 2);
+  let pattern =
+    match !pattern with
+    | Some pattern -> pattern
+    | None ->
+      (match Ocaml_rust_tiktok.encoding_pattern !encoding with
+       | Some pattern -> pattern
+       | None -> prerr_endline ("unknown encoding: " ^ !encoding); exit 2)
+  in
+  let texts = List.rev_map read_file !corpus in
+  let result =
+    Result.bind (Ocaml_rust_tiktok.train_bpe texts pattern !vocab_size) (fun ranks ->
+        Result.map (fun () -> List.length ranks) (Ocaml_rust_tiktok.write_tiktoken_file ranks !output))
+  in
+  match result with
+  | Ok n -> Printf.printf "Wrote %d tokens to %s\n" n !output
+  | Error e -> prerr_endline (Ocaml_rust_tiktok.string_of_error e); exit 1
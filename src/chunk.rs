@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+
+use crate::{check_disallowed_special, CoreBPE, Rank, TiktokenError};
+
+// Where a chunk may end when it has to be cut before its token budget runs out. `Sentence` falls
+// back to `Whitespace`, which falls back to cutting at the last piece that fits.
+#[derive(Clone, Copy, PartialEq, Eq, ocaml::FromValue)]
+#[ocaml::sig("Any | Whitespace | Sentence")]
+pub enum ChunkBoundary {
+    Any,
+    Whitespace,
+    Sentence,
+}
+
+#[derive(ocaml::FromValue)]
+#[ocaml::sig("{ max_tokens: int; overlap: int; boundary: chunk_boundary }")]
+pub struct ChunkOptions {
+    pub max_tokens: usize,
+    // Number of tokens at the end of a chunk that may be repeated at the start of the next one.
+    pub overlap: usize,
+    pub boundary: ChunkBoundary,
+}
+
+// A chunk of a document: the byte range `start..end` of the text and its tokens.
+pub struct Chunk {
+    pub start: usize,
+    pub end: usize,
+    pub tokens: Vec<Rank>,
+}
+
+// The unit a chunk boundary can fall on: a regex piece or special token, or a single token of a
+// piece too long to fit in one chunk.
+struct Unit {
+    start: usize,
+    end: usize,
+    first_token: usize,
+}
+
+impl ChunkBoundary {
+    // Whether cutting `text` at byte `pos` respects this boundary. Works on bytes since the
+    // tokens of an oversized piece can end inside a UTF-8 sequence.
+    fn accepts(self, text: &[u8], pos: usize) -> bool {
+        let (before, after) = text.split_at(pos);
+        let at_space = before.last().is_some_and(u8::is_ascii_whitespace)
+            || after.first().is_some_and(u8::is_ascii_whitespace);
+        match self {
+            ChunkBoundary::Any => true,
+            ChunkBoundary::Whitespace => at_space,
+            ChunkBoundary::Sentence => {
+                let ends_sentence =
+                    matches!(before.trim_ascii_end().last(), Some(b'.' | b'!' | b'?'));
+                (ends_sentence && at_space) || before.ends_with(b"\n") || after.starts_with(b"\n")
+            }
+        }
+    }
+
+    fn with_fallbacks(self) -> &'static [ChunkBoundary] {
+        match self {
+            ChunkBoundary::Any => &[],
+            ChunkBoundary::Whitespace => &[ChunkBoundary::Whitespace],
+            ChunkBoundary::Sentence => &[ChunkBoundary::Sentence, ChunkBoundary::Whitespace],
+        }
+    }
+}
+
+impl CoreBPE {
+    // Splits `text` into chunks of at most `options.max_tokens` tokens, encoding it once. Chunks
+    // end between pieces, preferring `options.boundary` once at least half the budget is used,
+    // and each chunk starts at most `options.overlap` tokens before the end of the previous one.
+    // The tokens of all chunks, minus the overlaps, are exactly `encode(text, allowed_special)`.
+    pub fn chunk(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        disallowed_special: &HashSet<&str>,
+        options: &ChunkOptions,
+    ) -> Result<Vec<Chunk>, TiktokenError> {
+        let max_tokens = options.max_tokens;
+        if max_tokens == 0 || options.overlap >= max_tokens {
+            return Err(TiktokenError::BadArgument(format!(
+                "chunk overlap {} must be smaller than max_tokens {}",
+                options.overlap, max_tokens
+            )));
+        }
+        check_disallowed_special(text, disallowed_special)?;
+
+        let mut tokens = vec![];
+        let mut units = vec![];
+        self._for_each_piece(text, allowed_special, |start, end, piece| {
+            if piece.len() <= max_tokens {
+                units.push(Unit { start, end, first_token: tokens.len() });
+            } else {
+                let mut pos = start;
+                for (i, &token) in piece.iter().enumerate() {
+                    let token_end = pos + self._token_len(token);
+                    units.push(Unit { start: pos, end: token_end, first_token: tokens.len() + i });
+                    pos = token_end;
+                }
+            }
+            tokens.extend_from_slice(piece);
+            true
+        })?;
+        let tokens_end = |k: usize| units.get(k + 1).map_or(tokens.len(), |u| u.first_token);
+
+        let mut chunks = vec![];
+        let mut first = 0;
+        // Every chunk has to end after the previous one, whatever boundary it prefers.
+        let mut prev_last = 0;
+        while first < units.len() {
+            let first_token = units[first].first_token;
+            let mut last = first;
+            while last < units.len() && tokens_end(last) - first_token <= max_tokens {
+                last += 1;
+            }
+            if last < units.len() {
+                let cut = options.boundary.with_fallbacks().iter().find_map(|boundary| {
+                    (prev_last.max(first) + 1..last)
+                        .rev()
+                        .take_while(|&k| 2 * (units[k].first_token - first_token) >= max_tokens)
+                        .find(|&k| boundary.accepts(text.as_bytes(), units[k].start))
+                });
+                last = cut.unwrap_or(last);
+            }
+
+            let end_token = tokens_end(last - 1);
+            chunks.push(Chunk {
+                start: units[first].start,
+                end: units[last - 1].end,
+                tokens: tokens[first_token..end_token].to_vec(),
+            });
+            if last == units.len() {
+                break;
+            }
+            prev_last = last;
+            // Start the next chunk as early as the overlap allows, as long as it still gets past
+            // the end of this one. Starting at `last` always works since no unit exceeds the budget.
+            first = (first + 1..=last)
+                .find(|&k| {
+                    end_token - units[k].first_token <= options.overlap
+                        && tokens_end(last) - units[k].first_token <= max_tokens
+                })
+                .unwrap();
+        }
+        Ok(chunks)
+    }
+}
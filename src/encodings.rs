@@ -0,0 +1,179 @@
+use ocaml::List;
+
+use crate::Rank;
+
+pub const ENDOFTEXT: &str = "<|endoftext|>";
+pub const FIM_PREFIX: &str = "<|fim_prefix|>";
+pub const FIM_MIDDLE: &str = "<|fim_middle|>";
+pub const FIM_SUFFIX: &str = "<|fim_suffix|>";
+pub const ENDOFPROMPT: &str = "<|endofprompt|>";
+
+const R50K_PATTERN: &str =
+    r"'(?:[sdmt]|ll|ve|re)| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+(?!\S)|\s+";
+
+const CL100K_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+const O200K_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n/]*",
+    r"|\s*[\r\n]+",
+    r"|\s+(?!\S)",
+    r"|\s+",
+);
+
+// Selects special tokens like the `allowed_special` / `disallowed_special` arguments of tiktoken's
+// `encode`: either every special token of the encoding or an explicit list.
+#[derive(ocaml::FromValue)]
+#[ocaml::sig("All | Tokens of string list")]
+pub enum SpecialTokens<'a> {
+    All,
+    Tokens(List<&'a [u8]>),
+}
+
+// Everything needed to build one of the standard encodings except the rank data itself.
+pub struct EncodingSpec {
+    pub name: &'static str,
+    pub pattern: &'static str,
+    pub special_tokens: &'static [(&'static str, Rank)],
+    // Largest token id plus one, used to check that the rank file matches the encoding.
+    pub n_vocab: usize,
+}
+
+pub const ENCODINGS: &[EncodingSpec] = &[
+    EncodingSpec {
+        name: "r50k_base",
+        pattern: R50K_PATTERN,
+        special_tokens: &[(ENDOFTEXT, 50256)],
+        n_vocab: 50257,
+    },
+    EncodingSpec {
+        name: "p50k_base",
+        pattern: R50K_PATTERN,
+        special_tokens: &[(ENDOFTEXT, 50256)],
+        n_vocab: 50281,
+    },
+    EncodingSpec {
+        name: "p50k_edit",
+        pattern: R50K_PATTERN,
+        special_tokens: &[
+            (ENDOFTEXT, 50256),
+            (FIM_PREFIX, 50281),
+            (FIM_MIDDLE, 50282),
+            (FIM_SUFFIX, 50283),
+        ],
+        n_vocab: 50284,
+    },
+    EncodingSpec {
+        name: "cl100k_base",
+        pattern: CL100K_PATTERN,
+        special_tokens: &[
+            (ENDOFTEXT, 100257),
+            (FIM_PREFIX, 100258),
+            (FIM_MIDDLE, 100259),
+            (FIM_SUFFIX, 100260),
+            (ENDOFPROMPT, 100276),
+        ],
+        n_vocab: 100277,
+    },
+    EncodingSpec {
+        name: "o200k_base",
+        pattern: O200K_PATTERN,
+        special_tokens: &[(ENDOFTEXT, 199999), (ENDOFPROMPT, 200018)],
+        n_vocab: 200019,
+    },
+];
+
+pub fn encoding_spec(name: &str) -> Option<&'static EncodingSpec> {
+    ENCODINGS.iter().find(|spec| spec.name == name)
+}
+
+// p50k_edit shares its rank file with p50k_base.
+pub fn rank_file_name(name: &str) -> String {
+    match name {
+        "p50k_edit" => "p50k_base.tiktoken".to_string(),
+        _ => format!("{}.tiktoken", name),
+    }
+}
+
+// Exact model names, checked before the prefixes below.
+const MODEL_TO_ENCODING: &[(&str, &str)] = &[
+    // chat
+    ("o1", "o200k_base"),
+    ("o3", "o200k_base"),
+    ("gpt-4o", "o200k_base"),
+    ("gpt-4", "cl100k_base"),
+    ("gpt-3.5-turbo", "cl100k_base"),
+    ("gpt-3.5", "cl100k_base"),
+    ("gpt-35-turbo", "cl100k_base"),
+    // base
+    ("davinci-002", "cl100k_base"),
+    ("babbage-002", "cl100k_base"),
+    // embeddings
+    ("text-embedding-ada-002", "cl100k_base"),
+    ("text-embedding-3-small", "cl100k_base"),
+    ("text-embedding-3-large", "cl100k_base"),
+    // DEPRECATED MODELS
+    // text (DEPRECATED)
+    ("text-davinci-003", "p50k_base"),
+    ("text-davinci-002", "p50k_base"),
+    ("text-davinci-001", "r50k_base"),
+    ("text-curie-001", "r50k_base"),
+    ("text-babbage-001", "r50k_base"),
+    ("text-ada-001", "r50k_base"),
+    ("davinci", "r50k_base"),
+    ("curie", "r50k_base"),
+    ("babbage", "r50k_base"),
+    ("ada", "r50k_base"),
+    // code (DEPRECATED)
+    ("code-davinci-002", "p50k_base"),
+    ("code-davinci-001", "p50k_base"),
+    ("code-cushman-002", "p50k_base"),
+    ("code-cushman-001", "p50k_base"),
+    ("davinci-codex", "p50k_base"),
+    ("cushman-codex", "p50k_base"),
+    // edit (DEPRECATED)
+    ("text-davinci-edit-001", "p50k_edit"),
+    ("code-davinci-edit-001", "p50k_edit"),
+    // old embeddings (DEPRECATED)
+    ("text-similarity-davinci-001", "r50k_base"),
+    ("text-similarity-curie-001", "r50k_base"),
+    ("text-similarity-babbage-001", "r50k_base"),
+    ("text-similarity-ada-001", "r50k_base"),
+    ("text-search-davinci-doc-001", "r50k_base"),
+    ("text-search-curie-doc-001", "r50k_base"),
+    ("text-search-babbage-doc-001", "r50k_base"),
+    ("text-search-ada-doc-001", "r50k_base"),
+    ("code-search-babbage-code-001", "r50k_base"),
+    ("code-search-ada-code-001", "r50k_base"),
+];
+
+// Prefixes for dated snapshots and fine-tunes. Order matters: the first match wins, so longer
+// prefixes ("ft:gpt-4o") must come before shorter ones ("ft:gpt-4").
+const MODEL_PREFIX_TO_ENCODING: &[(&str, &str)] = &[
+    ("o1-", "o200k_base"),
+    ("o3-", "o200k_base"),
+    ("chatgpt-4o-", "o200k_base"),
+    ("gpt-4o-", "o200k_base"),
+    ("gpt-4-", "cl100k_base"),
+    ("gpt-3.5-turbo-", "cl100k_base"),
+    ("gpt-35-turbo-", "cl100k_base"),
+    ("ft:gpt-4o", "o200k_base"),
+    ("ft:gpt-4", "cl100k_base"),
+    ("ft:gpt-3.5-turbo", "cl100k_base"),
+    ("ft:davinci-002", "cl100k_base"),
+    ("ft:babbage-002", "cl100k_base"),
+];
+
+pub fn encoding_name_for_model(model: &str) -> Option<&'static str> {
+    MODEL_TO_ENCODING
+        .iter()
+        .find(|(name, _)| *name == model)
+        .or_else(|| {
+            MODEL_PREFIX_TO_ENCODING
+                .iter()
+                .find(|(prefix, _)| model.starts_with(prefix))
+        })
+        .map(|&(_, encoding)| encoding)
+}
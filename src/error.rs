@@ -0,0 +1,99 @@
+use std::fmt;
+
+// Errors surfaced to OCaml. Every fallible export returns `('a, tiktoken_error) result`, so
+// OCaml callers can match on the variant instead of parsing messages.
+#[derive(Debug, Clone, PartialEq, Eq, ocaml::ToValue, ocaml::FromValue)]
+#[ocaml::sig("Invalid_pattern of string | Invalid_rank_file of string | Io_error of string | Duplicate_rank of int | Unknown_token of int | Unknown_token_at of int * int | Unknown_piece of string | Unknown_handle of int | Disallowed_special_token of string | Invalid_utf8 of int | Unknown_encoding of string | Unknown_model of string | Vocab_size_mismatch of int * int | Bad_argument of string | Invalid_snapshot of string")]
+pub enum TiktokenError {
+    // The regex pattern (or the regex built from the special tokens) does not compile.
+    InvalidPattern(String),
+    // A line of a .tiktoken rank file could not be parsed.
+    InvalidRankFile(String),
+    IoError(String),
+    // Two tokens share the same rank, so the encoder cannot be inverted.
+    DuplicateRank(ocaml::Int),
+    // Token payloads are OCaml ints rather than ranks so that an id outside the rank range can be
+    // reported as it was given.
+    UnknownToken(ocaml::Int),
+    // A token that cannot be decoded and its index in the input.
+    UnknownTokenAt(ocaml::Int, usize),
+    UnknownPiece(String),
+    // An integer ID that is not (or no longer) in the CoreBPE store.
+    UnknownHandle(usize),
+    DisallowedSpecialToken(String),
+    // Byte offset of the first invalid UTF-8 sequence.
+    InvalidUtf8(usize),
+    UnknownEncoding(String),
+    UnknownModel(String),
+    // Expected and actual vocabulary size.
+    VocabSizeMismatch(usize, usize),
+    // An argument outside its documented range, like a chunk overlap not smaller than the chunk.
+    BadArgument(String),
+    // A snapshot file that is truncated, corrupted or of an unsupported version.
+    InvalidSnapshot(String),
+}
+
+impl fmt::Display for TiktokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TiktokenError::InvalidPattern(e) => write!(f, "Invalid regex pattern: {}", e),
+            TiktokenError::InvalidRankFile(e) => write!(f, "Invalid rank file: {}", e),
+            TiktokenError::IoError(e) => write!(f, "I/O error: {}", e),
+            TiktokenError::DuplicateRank(rank) => write!(f, "Duplicate rank {}", rank),
+            TiktokenError::UnknownToken(token) => write!(f, "Token {} not found", token),
+            TiktokenError::UnknownTokenAt(token, position) => {
+                write!(f, "Token {} at position {} not found", token, position)
+            }
+            TiktokenError::UnknownPiece(piece) => write!(f, "Token not found for piece: {:?}", piece),
+            TiktokenError::UnknownHandle(id) => write!(f, "No CoreBPE instance with ID {}", id),
+            TiktokenError::DisallowedSpecialToken(token) => {
+                write!(f, "Encountered text corresponding to disallowed special token {:?}", token)
+            }
+            TiktokenError::InvalidUtf8(pos) => write!(f, "Invalid UTF-8 at byte {}", pos),
+            TiktokenError::UnknownEncoding(name) => write!(f, "Unknown encoding: {}", name),
+            TiktokenError::UnknownModel(model) => {
+                write!(f, "Could not automatically map {} to an encoding", model)
+            }
+            TiktokenError::VocabSizeMismatch(expected, actual) => write!(
+                f,
+                "Vocabulary size mismatch: expected {}, got {}",
+                expected, actual
+            ),
+            TiktokenError::BadArgument(e) => write!(f, "Bad argument: {}", e),
+            TiktokenError::InvalidSnapshot(e) => write!(f, "Invalid snapshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TiktokenError {}
+
+// Function to describe an error in a message for users, such as a command line tool's
+#[ocaml::func]
+#[ocaml::sig("tiktoken_error -> string")]
+pub fn string_of_error(error: TiktokenError) -> String {
+    error.to_string()
+}
+
+// How to handle bytes that are not valid UTF-8 when decoding to a string, like the `errors`
+// argument of tiktoken's `decode`: fail with `InvalidUtf8`, substitute U+FFFD, or drop them.
+#[derive(Clone, Copy, PartialEq, Eq, ocaml::FromValue)]
+#[ocaml::sig("Strict | Replace | Ignore")]
+pub enum DecodeErrors {
+    Strict,
+    Replace,
+    Ignore,
+}
+
+pub fn string_from_utf8(bytes: Vec<u8>, errors: DecodeErrors) -> Result<String, TiktokenError> {
+    match errors {
+        DecodeErrors::Strict => String::from_utf8(bytes)
+            .map_err(|e| TiktokenError::InvalidUtf8(e.utf8_error().valid_up_to())),
+        DecodeErrors::Replace => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+        DecodeErrors::Ignore => Ok(bytes.utf8_chunks().map(|chunk| chunk.valid()).collect()),
+    }
+}
+
+// Validates text coming from OCaml, whose strings are arbitrary bytes.
+pub fn text_from_bytes(bytes: &[u8]) -> Result<&str, TiktokenError> {
+    std::str::from_utf8(bytes).map_err(|e| TiktokenError::InvalidUtf8(e.valid_up_to()))
+}
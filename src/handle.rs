@@ -0,0 +1,558 @@
+use std::collections::{HashSet, LinkedList};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use ocaml::bigarray::Array1;
+use ocaml::{Custom, FromValue, List, Pointer, ToValue, Value};
+
+use crate::chunk::ChunkOptions;
+use crate::{
+    allowed_special_from_list, decode_maybe_unlocked, default_rank_file_path, encoder_from_value,
+    encodings, resolve_special_tokens, special_tokens_from_value, text_from_bytes, texts_from_list,
+    token_from_int, tokens_from_ints, tokens_of_bigarray, tokens_to_bigarray, tokens_to_ints,
+    unknown_token_as_given, with_input_unlocked, without_runtime_lock, CoreBPE, DecodeErrors,
+    RELEASE_RUNTIME_TOKENS, Rank, Span, SpecialTokens, TiktokenError,
+};
+
+// A CoreBPE owned by the OCaml garbage collector. On the OCaml side this is the abstract type
+// `encoding`; the default finalizer drops the Rust value once the handle is unreachable, so a
+// handle can never refer to a missing instance. The CoreBPE sits behind an Arc because the GC may
+// move the custom block: work done without the runtime lock holds its own reference instead.
+#[ocaml::sig]
+pub struct Encoding {
+    pub(crate) bpe: Arc<CoreBPE>,
+}
+
+ocaml::custom!(Encoding);
+
+// Number of encodings that have not been finalized yet.
+static LIVE_ENCODINGS: AtomicUsize = AtomicUsize::new(0);
+
+impl Encoding {
+    // The block is allocated with the size of the vocabulary as its out-of-heap memory, so that
+    // dropped handles make the GC collect sooner instead of letting tens of MB per vocabulary pile
+    // up behind a few words of OCaml heap. Encodings derived from one another share their rank
+    // tables but each count them.
+    fn alloc(bpe: CoreBPE) -> Pointer<Encoding> {
+        let mem = bpe.memory_usage();
+        let value = unsafe {
+            ocaml::Value::new(ocaml::sys::caml_alloc_custom_mem(
+                Encoding::ops() as *const _ as *mut ocaml::sys::custom_operations,
+                std::mem::size_of::<Encoding>(),
+                mem,
+            ))
+        };
+        let mut encoding = Pointer::<Encoding>::from_value(value);
+        encoding.set(Encoding { bpe: Arc::new(bpe) });
+        LIVE_ENCODINGS.fetch_add(1, Ordering::Relaxed);
+        encoding
+    }
+}
+
+impl Drop for Encoding {
+    fn drop(&mut self) {
+        LIVE_ENCODINGS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Function to count the encodings whose finalizer has not run yet
+#[ocaml::func]
+#[ocaml::sig("unit -> int")]
+pub fn encoding_live_handles() -> usize {
+    LIVE_ENCODINGS.load(Ordering::Relaxed)
+}
+
+// Helper function to copy resolved special tokens so they can be used without the runtime lock
+fn owned_tokens(tokens: HashSet<&str>) -> Vec<String> {
+    tokens.into_iter().map(str::to_string).collect()
+}
+
+// Function to create a new encoding from an encoder list, special tokens and a pattern
+#[ocaml::func]
+#[ocaml::sig("(bytes * int) list -> (string * int) list -> string -> (encoding, tiktoken_error) result")]
+pub fn encoding_new(
+    encoder: Value,
+    special_tokens_encoder: Value,
+    pattern: &[u8],
+) -> Result<Pointer<Encoding>, TiktokenError> {
+    let encoder_map = encoder_from_value(encoder)?;
+    let special_tokens_map = special_tokens_from_value(special_tokens_encoder)?;
+    let bpe = CoreBPE::new(encoder_map, special_tokens_map, text_from_bytes(pattern)?)?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to create a new encoding from a .tiktoken rank file on disk
+#[ocaml::func]
+#[ocaml::sig("string -> (string * int) list -> string -> (encoding, tiktoken_error) result")]
+pub fn encoding_new_from_file(
+    path: &[u8],
+    special_tokens_encoder: Value,
+    pattern: &[u8],
+) -> Result<Pointer<Encoding>, TiktokenError> {
+    let special_tokens_map = special_tokens_from_value(special_tokens_encoder)?;
+    let bpe = CoreBPE::from_tiktoken_file(
+        text_from_bytes(path)?,
+        special_tokens_map,
+        text_from_bytes(pattern)?,
+    )?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to create a new encoding from the contents of a .tiktoken rank file
+#[ocaml::func]
+#[ocaml::sig("string -> (string * int) list -> string -> (encoding, tiktoken_error) result")]
+pub fn encoding_new_from_string(
+    contents: &[u8],
+    special_tokens_encoder: Value,
+    pattern: &[u8],
+) -> Result<Pointer<Encoding>, TiktokenError> {
+    let special_tokens_map = special_tokens_from_value(special_tokens_encoder)?;
+    let bpe =
+        CoreBPE::from_tiktoken_bytes(contents, special_tokens_map, text_from_bytes(pattern)?)?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to create an encoding from a snapshot written by encoding_save_snapshot
+#[ocaml::func]
+#[ocaml::sig("string -> (encoding, tiktoken_error) result")]
+pub fn encoding_load_snapshot(path: &[u8]) -> Result<Pointer<Encoding>, TiktokenError> {
+    let bpe = CoreBPE::from_snapshot_file(text_from_bytes(path)?)?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to save an encoding as a binary snapshot, which loads much faster than a rank file
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> (unit, tiktoken_error) result")]
+pub fn encoding_save_snapshot(encoding: &Encoding, path: &[u8]) -> Result<(), TiktokenError> {
+    encoding.bpe.save_snapshot_file(text_from_bytes(path)?)
+}
+
+// Function to create one of the standard encodings (cl100k_base, o200k_base, ...), reading its
+// rank file from the directory named by the TIKTOKEN_RANKS_DIR environment variable
+#[ocaml::func]
+#[ocaml::sig("string -> (encoding, tiktoken_error) result")]
+pub fn get_encoding(name: &[u8]) -> Result<Pointer<Encoding>, TiktokenError> {
+    let name = text_from_bytes(name)?;
+    let bpe = CoreBPE::from_encoding_name(name, &default_rank_file_path(name))?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to create one of the standard encodings from an explicit rank file path
+#[ocaml::func]
+#[ocaml::sig("string -> string -> (encoding, tiktoken_error) result")]
+pub fn get_encoding_from_file(name: &[u8], path: &[u8]) -> Result<Pointer<Encoding>, TiktokenError> {
+    let bpe = CoreBPE::from_encoding_name(text_from_bytes(name)?, text_from_bytes(path)?)?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to create the encoding used by a model, like get_encoding
+#[ocaml::func]
+#[ocaml::sig("string -> (encoding, tiktoken_error) result")]
+pub fn encoding_for_model(model: &[u8]) -> Result<Pointer<Encoding>, TiktokenError> {
+    let model = text_from_bytes(model)?;
+    let name = encodings::encoding_name_for_model(model)
+        .ok_or_else(|| TiktokenError::UnknownModel(model.to_string()))?;
+    let bpe = CoreBPE::from_encoding_name(name, &default_rank_file_path(name))?;
+    Ok(Encoding::alloc(bpe))
+}
+
+// Function to derive an encoding from an existing one, removing the special tokens in `remove`,
+// adding (or re-ranking) those in `add` and replacing the pattern if one is given. The ordinary
+// token tables are shared with `encoding` rather than copied.
+#[ocaml::func]
+#[ocaml::sig("encoding -> (string * int) list -> string list -> string option -> (encoding, tiktoken_error) result")]
+pub fn encoding_extend(
+    encoding: &Encoding,
+    add: Value,
+    remove: List<&[u8]>,
+    pattern: Option<&[u8]>,
+) -> Result<Pointer<Encoding>, TiktokenError> {
+    let add = special_tokens_from_value(add)?;
+    let remove = allowed_special_from_list(&remove.into_vec())?;
+    let pattern = pattern.map(text_from_bytes).transpose()?;
+    let bpe = encoding.bpe.with_special_tokens(add, &remove, pattern)?;
+    Ok(Encoding::alloc(bpe))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> (int array, tiktoken_error) result")]
+pub fn encoding_encode_ordinary(encoding: &Encoding, text: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| bpe.encode_ordinary(text)).map(tokens_to_ints)
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> (int array, tiktoken_error) result")]
+pub fn encoding_encode(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let allowed_special = texts_from_list(allowed_special)?;
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.encode(text, allowed_special.iter().map(String::as_str).collect())
+    })
+    .map(tokens_to_ints)
+}
+
+// Function to encode text with tiktoken's special token semantics: special tokens in
+// `allowed_special` are encoded as such, and text matching any token in `disallowed_special` is an
+// error. `encoding_encode_with_special enc text (Tokens []) All` is the tiktoken default.
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> special_tokens -> special_tokens -> (int array, tiktoken_error) result")]
+pub fn encoding_encode_with_special(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: SpecialTokens<'_>,
+    disallowed_special: SpecialTokens<'_>,
+) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let (allowed_special, disallowed_special) =
+        resolve_special_tokens(&bpe, allowed_special, disallowed_special)?;
+    let (allowed_special, disallowed_special) =
+        (owned_tokens(allowed_special), owned_tokens(disallowed_special));
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.encode_with_special_check(
+            text,
+            allowed_special.iter().map(String::as_str).collect(),
+            &disallowed_special.iter().map(String::as_str).collect(),
+        )
+    })
+    .map(tokens_to_ints)
+}
+
+// Function to encode text and return, for each token, the byte range `(start, end)` of the text it
+// encodes
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> (int array * (int * int) array, tiktoken_error) result")]
+pub fn encoding_encode_with_spans(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<(Vec<ocaml::Int>, Vec<Span>), TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let allowed_special = texts_from_list(allowed_special)?;
+    let (tokens, spans) = with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.encode_with_spans(text, &allowed_special.iter().map(String::as_str).collect())
+    })?;
+    Ok((tokens_to_ints(tokens), spans))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> bytes -> (int array, tiktoken_error) result")]
+pub fn encoding_encode_bytes(encoding: &Encoding, bytes: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    with_input_unlocked(gc, bytes, |bytes| bpe.encode_bytes(bytes)).map(tokens_to_ints)
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> (int array * int array list, tiktoken_error) result")]
+pub fn encoding_encode_with_unstable(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<(Vec<ocaml::Int>, LinkedList<Vec<ocaml::Int>>), TiktokenError> {
+    let allowed_special = allowed_special.into_vec();
+    let (tokens, completions) = encoding.bpe.encode_with_unstable(
+        text_from_bytes(text)?,
+        allowed_special_from_list(&allowed_special)?,
+    )?;
+    Ok((tokens_to_ints(tokens), completions.into_iter().map(tokens_to_ints).collect()))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> bytes -> (int, tiktoken_error) result")]
+pub fn encoding_encode_single_token(encoding: &Encoding, piece: &[u8]) -> Result<ocaml::Int, TiktokenError> {
+    Ok(encoding.bpe.encode_single_token(piece)? as ocaml::Int)
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> bytes -> (int array, tiktoken_error) result")]
+pub fn encoding_encode_single_piece(encoding: &Encoding, piece: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    encoding.bpe.encode_single_piece(piece).map(tokens_to_ints)
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> int array -> (bytes, tiktoken_error) result")]
+pub fn encoding_decode_bytes(encoding: &Encoding, tokens: Vec<ocaml::Int>) -> Result<Value, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let ranks = tokens_from_ints(&tokens);
+    let bytes = decode_maybe_unlocked(gc, ranks.len(), || bpe.decode_bytes(&ranks))
+        .map_err(|e| unknown_token_as_given(&tokens, e))?;
+    Ok(bytes.as_slice().to_value(gc))
+}
+
+// Function to decode tokens to a string, handling invalid UTF-8 as selected by `errors`
+#[ocaml::func]
+#[ocaml::sig("encoding -> int array -> decode_errors -> (string, tiktoken_error) result")]
+pub fn encoding_decode(
+    encoding: &Encoding,
+    tokens: Vec<ocaml::Int>,
+    errors: DecodeErrors,
+) -> Result<String, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let ranks = tokens_from_ints(&tokens);
+    decode_maybe_unlocked(gc, ranks.len(), || bpe.decode(&ranks, errors))
+        .map_err(|e| unknown_token_as_given(&tokens, e))
+}
+
+// Function to decode tokens, substituting `replacement` for every token that is not in the
+// vocabulary instead of failing
+#[ocaml::func]
+#[ocaml::sig("encoding -> int array -> bytes -> bytes")]
+pub fn encoding_decode_bytes_lossy(encoding: &Encoding, tokens: Vec<ocaml::Int>, replacement: &[u8]) -> Value {
+    let bpe = Arc::clone(&encoding.bpe);
+    let replacement = replacement.to_vec();
+    let ranks = tokens_from_ints(&tokens);
+    decode_maybe_unlocked(gc, ranks.len(), || bpe.decode_bytes_lossy(&ranks, &replacement))
+        .as_slice()
+        .to_value(gc)
+}
+
+// Bigarray variants of encoding_encode_ordinary, encoding_encode and encoding_decode_bytes. Tokens
+// travel as one flat int32 buffer, so large documents need no per-token allocation.
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> ((int32, int32_elt, c_layout) Array1.t, tiktoken_error) result")]
+pub fn encoding_encode_ordinary_bigarray(
+    encoding: &Encoding,
+    text: &[u8],
+) -> Result<Array1<i32>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let tokens = with_input_unlocked(gc, text_from_bytes(text)?, |text| bpe.encode_ordinary(text))?;
+    Ok(tokens_to_bigarray(&tokens))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> ((int32, int32_elt, c_layout) Array1.t, tiktoken_error) result")]
+pub fn encoding_encode_bigarray(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<Array1<i32>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let allowed_special = texts_from_list(allowed_special)?;
+    let tokens = with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.encode(text, allowed_special.iter().map(String::as_str).collect())
+    })?;
+    Ok(tokens_to_bigarray(&tokens))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> (int32, int32_elt, c_layout) Array1.t -> (bytes, tiktoken_error) result")]
+pub fn encoding_decode_bytes_bigarray(
+    encoding: &Encoding,
+    tokens: Array1<i32>,
+) -> Result<Value, TiktokenError> {
+    let (tokens, negative) = tokens_of_bigarray(&tokens);
+    let bytes = if tokens.len() < RELEASE_RUNTIME_TOKENS {
+        encoding.bpe.decode_bytes(tokens)?
+    } else {
+        // The bigarray's buffer is only guaranteed to live while we hold the runtime lock.
+        let tokens = tokens.to_vec();
+        let bpe = Arc::clone(&encoding.bpe);
+        without_runtime_lock(gc, || bpe.decode_bytes(&tokens))?
+    };
+    // An unknown token before the first negative value was reported above.
+    if let Some((token, i)) = negative {
+        return Err(TiktokenError::UnknownTokenAt(token, i));
+    }
+    Ok(bytes.as_slice().to_value(gc))
+}
+
+// Function to decode tokens to a string along with the character and byte offset where each
+// token starts in it
+#[ocaml::func]
+#[ocaml::sig("encoding -> int array -> (string * int array * int array, tiktoken_error) result")]
+pub fn encoding_decode_with_offsets(
+    encoding: &Encoding,
+    tokens: Vec<ocaml::Int>,
+) -> Result<(String, Vec<usize>, Vec<usize>), TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let ranks = tokens_from_ints(&tokens);
+    decode_maybe_unlocked(gc, ranks.len(), || bpe.decode_with_offsets(&ranks))
+        .map_err(|e| unknown_token_as_given(&tokens, e))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> int -> (bytes, tiktoken_error) result")]
+pub fn encoding_decode_single_token_bytes(
+    encoding: &Encoding,
+    token: ocaml::Int,
+) -> Result<Value, TiktokenError> {
+    let bytes = encoding.bpe.decode_single_token_bytes(token_from_int(token)?)?;
+    Ok(bytes.as_slice().to_value(gc))
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> bytes array")]
+pub fn encoding_token_byte_values(encoding: &Encoding) -> Vec<Value> {
+    encoding
+        .bpe
+        .token_byte_values()
+        .iter()
+        .map(|bytes| bytes.as_slice().to_value(gc))
+        .collect()
+}
+
+// Function to encode a batch of documents on `num_threads` threads (0 for one per core). The
+// documents are copied out first and the OCaml runtime lock is released while they are encoded.
+#[ocaml::func]
+#[ocaml::sig("encoding -> string list -> int -> (int array list, tiktoken_error) result")]
+pub fn encoding_encode_ordinary_batch(
+    encoding: &Encoding,
+    texts: List<&[u8]>,
+    num_threads: usize,
+) -> Result<LinkedList<Vec<ocaml::Int>>, TiktokenError> {
+    let texts = texts_from_list(texts)?;
+    let bpe = Arc::clone(&encoding.bpe);
+    let tokens = without_runtime_lock(gc, || bpe.encode_ordinary_batch(&texts, num_threads))?;
+    Ok(tokens.into_iter().map(tokens_to_ints).collect())
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> string list -> string list -> int -> (int array list, tiktoken_error) result")]
+pub fn encoding_encode_batch(
+    encoding: &Encoding,
+    texts: List<&[u8]>,
+    allowed_special: List<&[u8]>,
+    num_threads: usize,
+) -> Result<LinkedList<Vec<ocaml::Int>>, TiktokenError> {
+    let texts = texts_from_list(texts)?;
+    let allowed_special = texts_from_list(allowed_special)?;
+    let bpe = Arc::clone(&encoding.bpe);
+    let tokens = without_runtime_lock(gc, || {
+        let allowed_special: HashSet<&str> = allowed_special.iter().map(String::as_str).collect();
+        bpe.encode_batch(&texts, &allowed_special, num_threads)
+    })?;
+    Ok(tokens.into_iter().map(tokens_to_ints).collect())
+}
+
+#[ocaml::func]
+#[ocaml::sig("encoding -> int array list -> int -> (bytes list, tiktoken_error) result")]
+pub fn encoding_decode_batch(
+    encoding: &Encoding,
+    batch: List<Vec<ocaml::Int>>,
+    num_threads: usize,
+) -> Result<LinkedList<Value>, TiktokenError> {
+    let batch = batch.into_vec();
+    let ranks: Vec<Vec<Rank>> = batch.iter().map(|tokens| tokens_from_ints(tokens)).collect();
+    let bpe = Arc::clone(&encoding.bpe);
+    // The error does not say which document failed, so find it again to report the token as given.
+    let decoded = without_runtime_lock(gc, || bpe.decode_batch(&ranks, num_threads)).map_err(|e| {
+        match batch.iter().zip(&ranks).find(|(_, ranks)| bpe.decode_bytes(ranks).is_err()) {
+            Some((tokens, _)) => unknown_token_as_given(tokens, e),
+            None => e,
+        }
+    })?;
+    Ok(decoded.iter().map(|bytes| bytes.as_slice().to_value(gc)).collect())
+}
+
+// Function to count the tokens `encoding_encode_ordinary` would produce, without building them
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> (int, tiktoken_error) result")]
+pub fn encoding_count_tokens_ordinary(encoding: &Encoding, text: &[u8]) -> Result<usize, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| bpe.count_tokens_ordinary(text))
+}
+
+// Function to count the tokens `encoding_encode` would produce, without building them
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> (int, tiktoken_error) result")]
+pub fn encoding_count_tokens(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<usize, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let allowed_special = texts_from_list(allowed_special)?;
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.count_tokens(text, &allowed_special.iter().map(String::as_str).collect())
+    })
+}
+
+// Function to count the tokens of a batch of documents, like encoding_encode_batch
+#[ocaml::func]
+#[ocaml::sig("encoding -> string list -> string list -> int -> (int array, tiktoken_error) result")]
+pub fn encoding_count_tokens_batch(
+    encoding: &Encoding,
+    texts: List<&[u8]>,
+    allowed_special: List<&[u8]>,
+    num_threads: usize,
+) -> Result<Vec<usize>, TiktokenError> {
+    let texts = texts_from_list(texts)?;
+    let allowed_special = texts_from_list(allowed_special)?;
+    let bpe = Arc::clone(&encoding.bpe);
+    without_runtime_lock(gc, || {
+        let allowed_special: HashSet<&str> = allowed_special.iter().map(String::as_str).collect();
+        bpe.count_tokens_batch(&texts, &allowed_special, num_threads)
+    })
+}
+
+// Function to encode the longest prefix of a text that fits in `max_tokens` tokens. Returns the
+// tokens and the byte offset where the kept prefix ends; text is only cut between regex pieces.
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> int -> (int array * int, tiktoken_error) result")]
+pub fn encoding_encode_truncated(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+    max_tokens: usize,
+) -> Result<(Vec<ocaml::Int>, usize), TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let allowed_special = texts_from_list(allowed_special)?;
+    let (tokens, offset) = with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        let allowed_special = allowed_special.iter().map(String::as_str).collect();
+        bpe.encode_truncated(text, &allowed_special, max_tokens)
+    })?;
+    Ok((tokens_to_ints(tokens), offset))
+}
+
+// Function to encode the longest suffix of a text that fits in `max_tokens` tokens. Returns the
+// tokens and the byte offset where the kept suffix starts.
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> string list -> int -> (int array * int, tiktoken_error) result")]
+pub fn encoding_encode_truncated_tail(
+    encoding: &Encoding,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+    max_tokens: usize,
+) -> Result<(Vec<ocaml::Int>, usize), TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let allowed_special = texts_from_list(allowed_special)?;
+    let (tokens, offset) = with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        let allowed_special = allowed_special.iter().map(String::as_str).collect();
+        bpe.encode_truncated_tail(text, &allowed_special, max_tokens)
+    })?;
+    Ok((tokens_to_ints(tokens), offset))
+}
+
+// Function to split a document into chunks of at most `options.max_tokens` tokens, returning each
+// chunk's byte range and tokens. Special tokens are handled as in encoding_encode_with_special.
+#[ocaml::func]
+#[ocaml::sig("encoding -> string -> chunk_options -> special_tokens -> special_tokens -> ((int * int * int array) list, tiktoken_error) result")]
+pub fn encoding_chunk(
+    encoding: &Encoding,
+    text: &[u8],
+    options: ChunkOptions,
+    allowed_special: SpecialTokens<'_>,
+    disallowed_special: SpecialTokens<'_>,
+) -> Result<LinkedList<(usize, usize, Vec<ocaml::Int>)>, TiktokenError> {
+    let bpe = Arc::clone(&encoding.bpe);
+    let (allowed_special, disallowed_special) =
+        resolve_special_tokens(&bpe, allowed_special, disallowed_special)?;
+    let (allowed_special, disallowed_special) =
+        (owned_tokens(allowed_special), owned_tokens(disallowed_special));
+    let chunks = with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.chunk(
+            text,
+            &allowed_special.iter().map(String::as_str).collect(),
+            &disallowed_special.iter().map(String::as_str).collect(),
+            &options,
+        )
+    })?;
+    Ok(chunks
+        .into_iter()
+        .map(|chunk| (chunk.start, chunk.end, tokens_to_ints(chunk.tokens)))
+        .collect())
+}
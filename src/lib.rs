@@ -1,18 +1,55 @@
 #![allow(clippy::borrow_deref_ref)]
 
-use std::sync::{Arc, Mutex};
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Once, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use bstr::ByteSlice;
 use fancy_regex::Regex;
-use std::collections::{HashMap, HashSet};
-use ocaml::{List, Value, FromValue, ToValue, Runtime};
+use std::collections::{HashMap, HashSet, LinkedList};
+use ocaml::bigarray::Array1;
+use ocaml::{List, Value, FromValue, ToValue};
 use lazy_static::lazy_static;
 
+mod chunk;
+mod encodings;
+mod error;
+mod handle;
+mod load;
+mod snapshot;
+mod stream;
+mod train;
+
+use encodings::SpecialTokens;
+pub use error::TiktokenError;
+use error::{string_from_utf8, text_from_bytes, DecodeErrors};
+
 type Rank = u32;
+// Byte range `(start, end)` of the input text.
+type Span = (usize, usize);
 
 const MAX_NUM_THREADS: usize = 128;
 
+// Pieces at least this long are merged with `_byte_pair_merge_large`. Below it the simple
+// vector scan wins thanks to cache locality.
+const LARGE_PIECE_THRESHOLD: usize = 500;
+
+// Encode inputs of at least this many bytes, and decode inputs of at least this many tokens, are
+// copied out and processed with the OCaml runtime lock released. Below that the copy and the lock
+// handoff cost more than other threads would gain.
+const RELEASE_RUNTIME_BYTES: usize = 16 * 1024;
+const RELEASE_RUNTIME_TOKENS: usize = 4 * 1024;
+
 fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    if piece.len() >= LARGE_PIECE_THRESHOLD {
+        _byte_pair_merge_large(ranks, piece)
+    } else {
+        _byte_pair_merge_small(ranks, piece)
+    }
+}
+
+fn _byte_pair_merge_small(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
     // This is a vector of (start, rank).
     // The rank is of the pair starting at position start.
     let mut parts = Vec::with_capacity(piece.len() + 1);
@@ -70,32 +107,147 @@ fn _byte_pair_merge(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize,
     parts
 }
 
-pub fn byte_pair_encode(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<Rank> {
-    assert!(piece.len() > 1);
-    _byte_pair_merge(&ranks, &piece)
+// Same result as `_byte_pair_merge_small`, in O(m log n): the parts form a doubly linked list and the
+// candidate merges live in a min-heap keyed by (rank, start), so ties still go to the leftmost
+// pair. Heap entries are invalidated lazily by comparing against the part's current rank.
+fn _byte_pair_merge_large(ranks: &HashMap<Vec<u8>, Rank>, piece: &[u8]) -> Vec<(usize, Rank)> {
+    use std::cmp::Reverse;
+    use std::collections::BinaryHeap;
+
+    let n = piece.len();
+    // Part i starts at byte i; `next[i] == n` marks the last part.
+    let mut prev: Vec<usize> = (0..n).map(|i| i.wrapping_sub(1)).collect();
+    let mut next: Vec<usize> = (1..=n).collect();
+    let mut rank: Vec<Rank> = vec![Rank::MAX; n];
+    let mut heap = BinaryHeap::with_capacity(n);
+
+    let pair_rank = |next: &[usize], i: usize| -> Rank {
+        let j = next[i];
+        if j >= n {
+            return Rank::MAX;
+        }
+        let end = next[j];
+        *ranks.get(&piece[i..end]).unwrap_or(&Rank::MAX)
+    };
+
+    for (i, r) in rank.iter_mut().enumerate().take(n - 1) {
+        *r = pair_rank(&next, i);
+        if *r != Rank::MAX {
+            heap.push(Reverse((*r, i)));
+        }
+    }
+
+    while let Some(Reverse((r, i))) = heap.pop() {
+        if rank[i] != r {
+            // Stale: part i was merged away or its right neighbour changed.
+            continue;
+        }
+        let j = next[i];
+        next[i] = next[j];
+        if next[j] < n {
+            prev[next[j]] = i;
+        }
+        rank[j] = Rank::MAX;
+
+        rank[i] = pair_rank(&next, i);
+        if rank[i] != Rank::MAX {
+            heap.push(Reverse((rank[i], i)));
+        }
+        if i > 0 {
+            let p = prev[i];
+            rank[p] = pair_rank(&next, p);
+            if rank[p] != Rank::MAX {
+                heap.push(Reverse((rank[p], p)));
+            }
+        }
+    }
+
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < n {
+        parts.push((i, Rank::MAX));
+        i = next[i];
+    }
+    parts.push((n, Rank::MAX));
+    parts
+}
+
+fn unknown_piece(piece: &[u8]) -> TiktokenError {
+    TiktokenError::UnknownPiece(String::from_utf8_lossy(piece).into_owned())
+}
+
+// A pattern that compiles can still fail while matching, for example when it hits fancy-regex's
+// backtrack limit.
+pub(crate) fn match_error(e: fancy_regex::Error) -> TiktokenError {
+    TiktokenError::InvalidPattern(e.to_string())
+}
+
+// Fails with `UnknownPiece` if a byte of `piece` has no token and is not covered by a merge, which
+// can happen with vocabularies that do not include all 256 single bytes.
+pub fn byte_pair_encode(
+    piece: &[u8],
+    ranks: &HashMap<Vec<u8>, Rank>,
+) -> Result<Vec<Rank>, TiktokenError> {
+    if piece.is_empty() {
+        return Ok(vec![]);
+    }
+    _byte_pair_merge(ranks, piece)
         .windows(2)
-        .map(|part| ranks[&piece[part[0].0..part[1].0]])
+        .map(|part| {
+            let part = &piece[part[0].0..part[1].0];
+            ranks.get(part).copied().ok_or_else(|| unknown_piece(part))
+        })
         .collect()
 }
 
+// Number of tokens `byte_pair_encode` would produce, without building them.
+pub fn byte_pair_count(piece: &[u8], ranks: &HashMap<Vec<u8>, Rank>) -> Result<usize, TiktokenError> {
+    if piece.is_empty() {
+        return Ok(0);
+    }
+    let parts = _byte_pair_merge(ranks, piece);
+    // Parts longer than a byte are merges, which are tokens by construction.
+    for part in parts.windows(2) {
+        let part = &piece[part[0].0..part[1].0];
+        if part.len() == 1 && !ranks.contains_key(part) {
+            return Err(unknown_piece(part));
+        }
+    }
+    Ok(parts.len() - 1)
+}
+
 pub fn byte_pair_split<'a>(piece: &'a [u8], ranks: &HashMap<Vec<u8>, Rank>) -> Vec<&'a [u8]> {
-    assert!(piece.len() > 1);
-    _byte_pair_merge(&ranks, &piece)
+    if piece.is_empty() {
+        return vec![];
+    }
+    _byte_pair_merge(ranks, piece)
         .windows(2)
         .map(|part| &piece[part[0].0..part[1].0])
         .collect()
 }
 
-// CoreBPE struct definition
+// Builds the regex that finds any of the special tokens.
+fn special_tokens_regex(
+    special_tokens_encoder: &HashMap<String, Rank>,
+) -> Result<Regex, TiktokenError> {
+    let _parts = special_tokens_encoder
+        .keys()
+        .map(|s| fancy_regex::escape(s))
+        .collect::<Vec<_>>();
+    Regex::new(&_parts.join("|")).map_err(|e| TiktokenError::InvalidPattern(e.to_string()))
+}
+
+// CoreBPE struct definition. The rank tables are behind Arcs so that encodings derived with
+// `with_special_tokens` share them.
 #[derive(Clone)]
 struct CoreBPE {
-    encoder: HashMap<Vec<u8>, Rank>,
+    encoder: Arc<HashMap<Vec<u8>, Rank>>,
     special_tokens_encoder: HashMap<String, Rank>,
-    decoder: HashMap<Rank, Vec<u8>>,
+    decoder: Arc<HashMap<Rank, Vec<u8>>>,
     special_tokens_decoder: HashMap<Rank, Vec<u8>>,
     regex_tls: Vec<Regex>,
     special_regex_tls: Vec<Regex>,
-    sorted_token_bytes: Vec<Vec<u8>>,
+    sorted_token_bytes: Arc<Vec<Vec<u8>>>,
 }
 
 impl CoreBPE {
@@ -103,22 +255,18 @@ impl CoreBPE {
         encoder: HashMap<Vec<u8>, Rank>,
         special_tokens_encoder: HashMap<String, Rank>,
         pattern: &str,
-    ) -> Result<Self, String> {
-        let regex = Regex::new(pattern).map_err(|e| e.to_string())?;
-
-        let special_regex = {
-            let _parts = special_tokens_encoder
-                .keys()
-                .map(|s| fancy_regex::escape(s))
-                .collect::<Vec<_>>();
-            Regex::new(&_parts.join("|")).map_err(|e| e.to_string())?
-        };
+    ) -> Result<Self, TiktokenError> {
+        let regex =
+            Regex::new(pattern).map_err(|e| TiktokenError::InvalidPattern(e.to_string()))?;
 
-        let decoder: HashMap<Rank, Vec<u8>> =
-            encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
+        let special_regex = special_tokens_regex(&special_tokens_encoder)?;
 
-        if encoder.len() != decoder.len() {
-            return Err("Encoder and decoder must be of equal length.".to_string());
+        // Encoder and decoder must be of equal length, i.e. no two tokens may share a rank.
+        let mut decoder: HashMap<Rank, Vec<u8>> = HashMap::with_capacity(encoder.len());
+        for (k, v) in &encoder {
+            if decoder.insert(*v, k.clone()).is_some() {
+                return Err(TiktokenError::DuplicateRank(*v as ocaml::Int));
+            }
         }
 
         let special_tokens_decoder: HashMap<Rank, Vec<u8>> = special_tokens_encoder
@@ -130,16 +278,138 @@ impl CoreBPE {
         sorted_token_bytes.sort();
 
         Ok(CoreBPE {
-            encoder,
+            encoder: Arc::new(encoder),
             special_tokens_encoder,
-            decoder,
+            decoder: Arc::new(decoder),
             special_tokens_decoder,
             regex_tls: vec![regex.clone(); MAX_NUM_THREADS],
             special_regex_tls: vec![special_regex.clone(); MAX_NUM_THREADS],
-            sorted_token_bytes,
+            sorted_token_bytes: Arc::new(sorted_token_bytes),
+        })
+    }
+
+    fn from_tiktoken_file(
+        path: &str,
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str,
+    ) -> Result<Self, TiktokenError> {
+        let encoder = load::load_tiktoken_bpe_file(path)?;
+        CoreBPE::new(encoder, special_tokens_encoder, pattern)
+    }
+
+    fn from_tiktoken_bytes(
+        contents: &[u8],
+        special_tokens_encoder: HashMap<String, Rank>,
+        pattern: &str,
+    ) -> Result<Self, TiktokenError> {
+        let encoder = load::load_tiktoken_bpe(contents)?;
+        CoreBPE::new(encoder, special_tokens_encoder, pattern)
+    }
+
+    fn from_encoding_name(name: &str, ranks_path: &str) -> Result<Self, TiktokenError> {
+        let spec = encodings::encoding_spec(name)
+            .ok_or_else(|| TiktokenError::UnknownEncoding(name.to_string()))?;
+        let encoder = load::load_tiktoken_bpe_file(ranks_path)?;
+        let special_tokens_encoder: HashMap<String, Rank> = spec
+            .special_tokens
+            .iter()
+            .map(|&(token, rank)| (token.to_string(), rank))
+            .collect();
+
+        let n_vocab = encoder
+            .values()
+            .chain(special_tokens_encoder.values())
+            .max()
+            .map_or(0, |&max| max as usize + 1);
+        if n_vocab != spec.n_vocab {
+            return Err(TiktokenError::VocabSizeMismatch(spec.n_vocab, n_vocab));
+        }
+
+        CoreBPE::new(encoder, special_tokens_encoder, spec.pattern)
+    }
+
+    // Derives a CoreBPE with the same ordinary tokens, sharing the rank tables rather than copying
+    // them. The special tokens in `remove` are dropped, then those in `add` are added or given a
+    // new rank; `pattern` replaces the regex if given. A special token may not take the rank of
+    // an ordinary token or of another special token.
+    fn with_special_tokens(
+        &self,
+        add: HashMap<String, Rank>,
+        remove: &HashSet<&str>,
+        pattern: Option<&str>,
+    ) -> Result<Self, TiktokenError> {
+        let mut special_tokens_encoder = self.special_tokens_encoder.clone();
+        for token in remove {
+            if special_tokens_encoder.remove(*token).is_none() {
+                return Err(TiktokenError::BadArgument(format!(
+                    "no special token {:?} to remove",
+                    token
+                )));
+            }
+        }
+        special_tokens_encoder.extend(add);
+
+        let mut special_tokens_decoder = HashMap::with_capacity(special_tokens_encoder.len());
+        for (token, &rank) in &special_tokens_encoder {
+            if self.decoder.contains_key(&rank)
+                || special_tokens_decoder.insert(rank, token.as_bytes().to_vec()).is_some()
+            {
+                return Err(TiktokenError::DuplicateRank(rank as ocaml::Int));
+            }
+        }
+
+        let regex_tls = match pattern {
+            Some(pattern) => {
+                let regex = Regex::new(pattern)
+                    .map_err(|e| TiktokenError::InvalidPattern(e.to_string()))?;
+                vec![regex; MAX_NUM_THREADS]
+            }
+            None => self.regex_tls.clone(),
+        };
+        let special_regex = special_tokens_regex(&special_tokens_encoder)?;
+
+        Ok(CoreBPE {
+            encoder: Arc::clone(&self.encoder),
+            special_tokens_encoder,
+            decoder: Arc::clone(&self.decoder),
+            special_tokens_decoder,
+            regex_tls,
+            special_regex_tls: vec![special_regex; MAX_NUM_THREADS],
+            sorted_token_bytes: Arc::clone(&self.sorted_token_bytes),
         })
     }
 
+    // Approximate heap usage in bytes: table contents plus per-entry bookkeeping. Compiled regexes
+    // are not included.
+    fn memory_usage(&self) -> usize {
+        use std::mem::size_of;
+
+        let bytes_entry = size_of::<Vec<u8>>() + size_of::<Rank>();
+        let encoder: usize = self.encoder.keys().map(|k| k.len() + bytes_entry).sum();
+        let decoder: usize = self.decoder.values().map(|v| v.len() + bytes_entry).sum();
+        let special_tokens_encoder: usize = self
+            .special_tokens_encoder
+            .keys()
+            .map(|k| k.len() + size_of::<String>() + size_of::<Rank>())
+            .sum();
+        let special_tokens_decoder: usize = self
+            .special_tokens_decoder
+            .values()
+            .map(|v| v.len() + bytes_entry)
+            .sum();
+        let sorted_token_bytes: usize = self
+            .sorted_token_bytes
+            .iter()
+            .map(|v| v.len() + size_of::<Vec<u8>>())
+            .sum();
+        size_of::<Self>()
+            + encoder
+            + decoder
+            + special_tokens_encoder
+            + special_tokens_decoder
+            + sorted_token_bytes
+    }
+
     fn _get_tl_regex(&self) -> &Regex {
         &self.regex_tls[hash_current_thread() % MAX_NUM_THREADS]
     }
@@ -160,51 +430,90 @@ impl CoreBPE {
         ret
     }
 
-    fn _encode_ordinary_native(&self, text: &str) -> Vec<Rank> {
+    fn _token_len(&self, token: Rank) -> usize {
+        self.decoder
+            .get(&token)
+            .or_else(|| self.special_tokens_decoder.get(&token))
+            .map_or(0, |bytes| bytes.len())
+    }
+
+    // Like `_decode_native`, but for tokens that did not come from this encoder: an unknown token
+    // is replaced by `replacement` if given, otherwise it is reported along with its index.
+    fn _decode_native_checked(
+        &self,
+        tokens: &[Rank],
+        replacement: Option<&[u8]>,
+    ) -> Result<Vec<u8>, TiktokenError> {
+        let mut ret = Vec::with_capacity(tokens.len() * 2);
+        for (i, token) in tokens.iter().enumerate() {
+            let token_bytes = self
+                .decoder
+                .get(token)
+                .or_else(|| self.special_tokens_decoder.get(token));
+            match (token_bytes, replacement) {
+                (Some(token_bytes), _) => ret.extend(token_bytes),
+                (None, Some(replacement)) => ret.extend(replacement),
+                (None, None) => return Err(TiktokenError::UnknownTokenAt(*token as ocaml::Int, i)),
+            }
+        }
+        Ok(ret)
+    }
+
+    fn _encode_ordinary_native(&self, text: &str) -> Result<Vec<Rank>, TiktokenError> {
         let regex = self._get_tl_regex();
         let mut ret = vec![];
         for mat in regex.find_iter(text) {
-            let piece = mat.unwrap().as_str().as_bytes();
+            let piece = mat.map_err(match_error)?.as_str().as_bytes();
             match self.encoder.get(piece) {
                 Some(token) => ret.push(*token),
-                None => ret.extend(&byte_pair_encode(piece, &self.encoder)),
+                None => ret.extend(&byte_pair_encode(piece, &self.encoder)?),
             }
         }
-        ret
+        Ok(ret)
     }
 
-    fn _encode_native(&self, text: &str, allowed_special: &HashSet<&str>) -> (Vec<Rank>, usize) {
+    // Finds the first allowed special token in `text` at or after `start`.
+    fn _find_allowed_special<'t>(
+        &self,
+        text: &'t str,
+        start: usize,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<Option<fancy_regex::Match<'t>>, TiktokenError> {
         let special_regex = self._get_tl_special_regex();
+        let mut start_find = start;
+        loop {
+            let Some(m) = special_regex.find_from_pos(text, start_find).map_err(match_error)? else {
+                return Ok(None);
+            };
+            if allowed_special.contains(&text[m.start()..m.end()]) {
+                return Ok(Some(m));
+            }
+            start_find = m.start() + 1;
+        }
+    }
+
+    fn _encode_native(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<(Vec<Rank>, usize), TiktokenError> {
         let regex = self._get_tl_regex();
         let mut ret = vec![];
 
         let mut start = 0;
         let mut last_piece_token_len = 0;
         loop {
-            let mut next_special;
-            let mut start_find = start;
-            loop {
-                next_special = special_regex.find_from_pos(text, start_find).unwrap();
-                match next_special {
-                    Some(m) => {
-                        if allowed_special.contains(&text[m.start()..m.end()]) {
-                            break;
-                        }
-                        start_find = m.start() + 1;
-                    }
-                    None => break,
-                }
-            }
+            let next_special = self._find_allowed_special(text, start, allowed_special)?;
             let end = next_special.map_or(text.len(), |m| m.start());
 
             for mat in regex.find_iter(&text[start..end]) {
-                let piece = mat.unwrap().as_str().as_bytes();
+                let piece = mat.map_err(match_error)?.as_str().as_bytes();
                 if let Some(token) = self.encoder.get(piece) {
                     last_piece_token_len = 1;
                     ret.push(*token);
                     continue;
                 }
-                let tokens = byte_pair_encode(piece, &self.encoder);
+                let tokens = byte_pair_encode(piece, &self.encoder)?;
                 last_piece_token_len = tokens.len();
                 ret.extend(&tokens);
             }
@@ -220,7 +529,103 @@ impl CoreBPE {
                 None => break,
             }
         }
-        (ret, last_piece_token_len)
+        Ok((ret, last_piece_token_len))
+    }
+
+    // Calls `f(start, end, special)` for every regex piece and allowed special token of `text`, in
+    // order, until it returns false. `special` is the token of an allowed special token and `None`
+    // for a regex piece, which is not encoded.
+    fn _for_each_piece_span(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        mut f: impl FnMut(usize, usize, Option<Rank>) -> bool,
+    ) -> Result<(), TiktokenError> {
+        let regex = self._get_tl_regex();
+        let mut start = 0;
+        loop {
+            let next_special = self._find_allowed_special(text, start, allowed_special)?;
+            let end = next_special.map_or(text.len(), |m| m.start());
+
+            for mat in regex.find_iter(&text[start..end]) {
+                let mat = mat.map_err(match_error)?;
+                if !f(start + mat.start(), start + mat.end(), None) {
+                    return Ok(());
+                }
+            }
+
+            match next_special {
+                Some(m) => {
+                    let token = self.special_tokens_encoder[m.as_str()];
+                    if !f(m.start(), m.end(), Some(token)) {
+                        return Ok(());
+                    }
+                    start = m.end();
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Calls `f(start, end, tokens)` for every regex piece and allowed special token of `text`, in
+    // order, until it returns false.
+    fn _for_each_piece(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        mut f: impl FnMut(usize, usize, &[Rank]) -> bool,
+    ) -> Result<(), TiktokenError> {
+        let mut result = Ok(());
+        self._for_each_piece_span(text, allowed_special, |start, end, special| {
+            let piece = &text.as_bytes()[start..end];
+            match special.or_else(|| self.encoder.get(piece).copied()) {
+                Some(token) => f(start, end, &[token]),
+                None => match byte_pair_encode(piece, &self.encoder) {
+                    Ok(tokens) => f(start, end, &tokens),
+                    Err(e) => {
+                        result = Err(e);
+                        false
+                    }
+                },
+            }
+        })?;
+        result
+    }
+
+    fn _count_ordinary_native(&self, text: &str) -> Result<usize, TiktokenError> {
+        let regex = self._get_tl_regex();
+        let mut count = 0;
+        for mat in regex.find_iter(text) {
+            let piece = mat.map_err(match_error)?.as_str().as_bytes();
+            count += if self.encoder.contains_key(piece) {
+                1
+            } else {
+                byte_pair_count(piece, &self.encoder)?
+            };
+        }
+        Ok(count)
+    }
+
+    fn _count_native(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<usize, TiktokenError> {
+        let mut count = 0;
+        let mut start = 0;
+        loop {
+            let next_special = self._find_allowed_special(text, start, allowed_special)?;
+            let end = next_special.map_or(text.len(), |m| m.start());
+            count += self._count_ordinary_native(&text[start..end])?;
+            match next_special {
+                Some(m) => {
+                    count += 1;
+                    start = m.end();
+                }
+                None => break,
+            }
+        }
+        Ok(count)
     }
 
     fn _increase_last_piece_token_len(
@@ -256,10 +661,10 @@ impl CoreBPE {
         &self,
         text: &str,
         allowed_special: &HashSet<&str>,
-    ) -> (Vec<Rank>, HashSet<Vec<Rank>>) {
-        let (tokens, last_piece_token_len) = self._encode_native(text, allowed_special);
+    ) -> Result<(Vec<Rank>, HashSet<Vec<Rank>>), TiktokenError> {
+        let (tokens, last_piece_token_len) = self._encode_native(text, allowed_special)?;
         if last_piece_token_len == 0 {
-            return (tokens, HashSet::new());
+            return Ok((tokens, HashSet::new()));
         }
         let (mut tokens, last_piece_token_len) =
             self._increase_last_piece_token_len(tokens, last_piece_token_len);
@@ -269,7 +674,7 @@ impl CoreBPE {
 
         let mut completions = HashSet::new();
         if unstable_bytes.is_empty() {
-            return (tokens, completions);
+            return Ok((tokens, completions));
         }
 
         let mut point = self
@@ -295,8 +700,8 @@ impl CoreBPE {
             {
                 let possibility = [prefix, self.sorted_token_bytes[point].as_slice()].concat();
                 let encoded = match std::str::from_utf8(&possibility) {
-                    Ok(s) => self._encode_ordinary_native(s),
-                    Err(_) => byte_pair_encode(&possibility, &self.encoder),
+                    Ok(s) => self._encode_ordinary_native(s)?,
+                    Err(_) => byte_pair_encode(&possibility, &self.encoder)?,
                 };
                 let mut seq = Vec::new();
                 let mut seq_len = 0;
@@ -320,34 +725,50 @@ impl CoreBPE {
                 let mut reencoded = byte_pair_encode(
                     &unstable_bytes[..unstable_bytes.len() - last_decoded.1],
                     &self.encoder,
-                );
+                )?;
                 reencoded.extend(byte_pair_encode(
                     &unstable_bytes[unstable_bytes.len() - last_decoded.1..],
                     &self.encoder,
-                ));
+                )?);
                 completions.insert(reencoded);
             }
         }
 
-        (tokens, completions)
+        Ok((tokens, completions))
     }
 
-    pub fn encode_ordinary(&self, text: &str) -> Vec<Rank> {
+    pub fn encode_ordinary(&self, text: &str) -> Result<Vec<Rank>, TiktokenError> {
         // Directly call the native encoding function
         self._encode_ordinary_native(text)
     }
 
-    pub fn encode(&self, text: &str, allowed_special: HashSet<&str>) -> Vec<Rank> {
+    pub fn encode(
+        &self,
+        text: &str,
+        allowed_special: HashSet<&str>,
+    ) -> Result<Vec<Rank>, TiktokenError> {
         // Directly call the native encoding function with allowed special tokens
-        self._encode_native(text, &allowed_special).0
+        Ok(self._encode_native(text, &allowed_special)?.0)
     }
 
-    pub fn encode_bytes(&self, bytes: &[u8]) -> Vec<Rank> {
+    // Like `encode`, but first rejects text containing any special token in `disallowed_special`,
+    // as tiktoken's `encode` does.
+    pub fn encode_with_special_check(
+        &self,
+        text: &str,
+        allowed_special: HashSet<&str>,
+        disallowed_special: &HashSet<&str>,
+    ) -> Result<Vec<Rank>, TiktokenError> {
+        check_disallowed_special(text, disallowed_special)?;
+        self.encode(text, allowed_special)
+    }
+
+    pub fn encode_bytes(&self, bytes: &[u8]) -> Result<Vec<Rank>, TiktokenError> {
         match std::str::from_utf8(bytes) {
             Ok(text) => self._encode_ordinary_native(text),
             Err(e) => {
                 let text = unsafe { std::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) };
-                let (tokens, last_piece_token_len) = self._encode_native(text, &HashSet::new());
+                let (tokens, last_piece_token_len) = self._encode_native(text, &HashSet::new())?;
                 let (mut tokens, last_piece_token_len) =
                     self._increase_last_piece_token_len(tokens, last_piece_token_len);
                 if !tokens.is_empty() && last_piece_token_len > 0 {
@@ -358,10 +779,10 @@ impl CoreBPE {
                     tokens.truncate(tokens.len() - last_piece_token_len);
                     match self.encoder.get(&unstable_bytes) {
                         Some(token) => tokens.push(*token),
-                        None => tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder)),
+                        None => tokens.extend(&byte_pair_encode(&unstable_bytes, &self.encoder)?),
                     }
                 }
-                tokens
+                Ok(tokens)
             }
         }
     }
@@ -370,14 +791,14 @@ impl CoreBPE {
         &self,
         text: &str,
         allowed_special: HashSet<&str>,
-    ) -> (Vec<Rank>, Vec<Vec<Rank>>) {
-        let (tokens, completions_set) = self._encode_unstable_native(text, &allowed_special);
+    ) -> Result<(Vec<Rank>, Vec<Vec<Rank>>), TiktokenError> {
+        let (tokens, completions_set) = self._encode_unstable_native(text, &allowed_special)?;
         let completions: Vec<Vec<Rank>> = completions_set.into_iter().collect();
-        (tokens, completions)
+        Ok((tokens, completions))
     }
 
 
-    pub fn encode_single_token(&self, piece: &[u8]) -> Result<Rank, String> {
+    pub fn encode_single_token(&self, piece: &[u8]) -> Result<Rank, TiktokenError> {
         if let Some(token) = self.encoder.get(piece).copied() {
             return Ok(token);
         }
@@ -386,32 +807,205 @@ impl CoreBPE {
                 return Ok(token);
             }
         }
-        Err(format!("Token not found for piece: {:?}", piece))
+        Err(TiktokenError::UnknownPiece(String::from_utf8_lossy(piece).into_owned()))
     }
 
-    pub fn encode_single_piece(&self, piece: &[u8]) -> Vec<Rank> {
+    pub fn encode_single_piece(&self, piece: &[u8]) -> Result<Vec<Rank>, TiktokenError> {
         if let Some(token) = self.encoder.get(piece) {
-            return vec![*token];
+            return Ok(vec![*token]);
         }
         byte_pair_encode(piece, &self.encoder)
     }
 
-    pub fn decode_bytes(&self, tokens: Vec<Rank>) -> Vec<u8> {
-        self._decode_native(&tokens)
+    pub fn decode_bytes(&self, tokens: &[Rank]) -> Result<Vec<u8>, TiktokenError> {
+        self._decode_native_checked(tokens, None)
+    }
+
+    pub fn decode(&self, tokens: &[Rank], errors: DecodeErrors) -> Result<String, TiktokenError> {
+        string_from_utf8(self._decode_native_checked(tokens, None)?, errors)
     }
 
-    pub fn decode_single_token_bytes(&self, token: Rank) -> Result<Vec<u8>, String> {
+    pub fn decode_bytes_lossy(&self, tokens: &[Rank], replacement: &[u8]) -> Vec<u8> {
+        self._decode_native_checked(tokens, Some(replacement))
+            .expect("decoding with a replacement cannot fail")
+    }
+
+    pub fn decode_single_token_bytes(&self, token: Rank) -> Result<Vec<u8>, TiktokenError> {
         if let Some(bytes) = self.decoder.get(&token) {
             return Ok(bytes.clone());
         }
         if let Some(bytes) = self.special_tokens_decoder.get(&token) {
             return Ok(bytes.clone());
         }
-        Err(format!("Token {} not found", token))
+        Err(TiktokenError::UnknownToken(token as ocaml::Int))
+    }
+
+    // Decodes `tokens` to text and returns, for each token, the character and byte offset in that
+    // text where it starts, like Python tiktoken's `decode_with_offsets`. A token that begins in
+    // the middle of a multibyte character is given the offsets of that character.
+    pub fn decode_with_offsets(
+        &self,
+        tokens: &[Rank],
+    ) -> Result<(String, Vec<usize>, Vec<usize>), TiktokenError> {
+        let is_continuation = |b: u8| (0x80..0xC0).contains(&b);
+        let mut bytes = Vec::with_capacity(tokens.len() * 2);
+        let mut char_offsets = Vec::with_capacity(tokens.len());
+        let mut byte_offsets = Vec::with_capacity(tokens.len());
+        let mut num_chars: usize = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            let token_bytes = self
+                .decoder
+                .get(token)
+                .or_else(|| self.special_tokens_decoder.get(token))
+                .ok_or(TiktokenError::UnknownTokenAt(*token as ocaml::Int, i))?;
+            if token_bytes.first().is_some_and(|&b| is_continuation(b)) {
+                char_offsets.push(num_chars.saturating_sub(1));
+                byte_offsets.push(bytes.iter().rposition(|&b| !is_continuation(b)).unwrap_or(0));
+            } else {
+                char_offsets.push(num_chars);
+                byte_offsets.push(bytes.len());
+            }
+            num_chars += token_bytes.iter().filter(|&&b| !is_continuation(b)).count();
+            bytes.extend_from_slice(token_bytes);
+        }
+        let text = string_from_utf8(bytes, DecodeErrors::Strict)?;
+        Ok((text, char_offsets, byte_offsets))
     }
 
     pub fn token_byte_values(&self) -> Vec<Vec<u8>> {
-        self.sorted_token_bytes.clone()
+        self.sorted_token_bytes.to_vec()
+    }
+
+    // Same as `encode_ordinary(text).len()` and `encode(text, allowed_special).len()`, without
+    // building the token vector.
+    pub fn count_tokens_ordinary(&self, text: &str) -> Result<usize, TiktokenError> {
+        self._count_ordinary_native(text)
+    }
+
+    pub fn count_tokens(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<usize, TiktokenError> {
+        self._count_native(text, allowed_special)
+    }
+
+    pub fn count_tokens_batch(
+        &self,
+        texts: &[String],
+        allowed_special: &HashSet<&str>,
+        num_threads: usize,
+    ) -> Result<Vec<usize>, TiktokenError> {
+        parallel_map(texts, num_threads, |text| self._count_native(text, allowed_special))
+            .into_iter()
+            .collect()
+    }
+
+    // Encodes like `encode` and also returns the byte range of `text` each token came from: the
+    // regex match for whole pieces, and the merge boundaries within a byte-pair encoded piece.
+    pub fn encode_with_spans(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<(Vec<Rank>, Vec<Span>), TiktokenError> {
+        let mut tokens = vec![];
+        let mut spans = vec![];
+        self._for_each_piece(text, allowed_special, |start, _, piece| {
+            let mut pos = start;
+            for &token in piece {
+                let end = pos + self._token_len(token);
+                spans.push((pos, end));
+                pos = end;
+            }
+            tokens.extend_from_slice(piece);
+            true
+        })?;
+        Ok((tokens, spans))
+    }
+
+    // Encodes the longest prefix of `text` that fits in `max_tokens` tokens, cutting only between
+    // pieces so the tokens are exactly the start of `encode(text, allowed_special)`. Returns the
+    // tokens and the byte offset where the kept text ends (`text.len()` if nothing was cut).
+    pub fn encode_truncated(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        max_tokens: usize,
+    ) -> Result<(Vec<Rank>, usize), TiktokenError> {
+        let mut tokens = vec![];
+        let mut offset = text.len();
+        self._for_each_piece(text, allowed_special, |start, _, piece| {
+            if tokens.len() + piece.len() > max_tokens {
+                offset = start;
+                return false;
+            }
+            tokens.extend_from_slice(piece);
+            true
+        })?;
+        Ok((tokens, offset))
+    }
+
+    // Like `encode_truncated`, but keeps the longest suffix. The offset is where the kept text
+    // starts (0 if nothing was cut).
+    pub fn encode_truncated_tail(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+        max_tokens: usize,
+    ) -> Result<(Vec<Rank>, usize), TiktokenError> {
+        let mut tokens = vec![];
+        // (end of piece, number of tokens up to and including the piece)
+        let mut piece_ends = vec![];
+        self._for_each_piece(text, allowed_special, |_, end, piece| {
+            tokens.extend_from_slice(piece);
+            piece_ends.push((end, tokens.len()));
+            true
+        })?;
+        let first_kept = tokens.len().saturating_sub(max_tokens);
+        if first_kept == 0 {
+            return Ok((tokens, 0));
+        }
+        // Drop every piece that has a token before `first_kept`.
+        let (offset, first_kept) = piece_ends
+            .into_iter()
+            .find(|&(_, num_tokens)| num_tokens >= first_kept)
+            .unwrap();
+        Ok((tokens.split_off(first_kept), offset))
+    }
+
+    pub fn encode_ordinary_batch(
+        &self,
+        texts: &[String],
+        num_threads: usize,
+    ) -> Result<Vec<Vec<Rank>>, TiktokenError> {
+        parallel_map(texts, num_threads, |text| self._encode_ordinary_native(text))
+            .into_iter()
+            .collect()
+    }
+
+    pub fn encode_batch(
+        &self,
+        texts: &[String],
+        allowed_special: &HashSet<&str>,
+        num_threads: usize,
+    ) -> Result<Vec<Vec<Rank>>, TiktokenError> {
+        parallel_map(texts, num_threads, |text| {
+            self._encode_native(text, allowed_special).map(|(tokens, _)| tokens)
+        })
+        .into_iter()
+        .collect()
+    }
+
+    // Decodes every document, failing with the first unknown token of the first document (in input
+    // order) that has one. The index in `UnknownTokenAt` is relative to that document.
+    pub fn decode_batch(
+        &self,
+        batch: &[Vec<Rank>],
+        num_threads: usize,
+    ) -> Result<Vec<Vec<u8>>, TiktokenError> {
+        parallel_map(batch, num_threads, |tokens| self._decode_native_checked(tokens, None))
+            .into_iter()
+            .collect()
     }
 
 
@@ -420,77 +1014,509 @@ impl CoreBPE {
 
 }
 
-// Global storage for CoreBPE instances
+// Global storage for CoreBPE instances. Instances are immutable once stored, so they are shared
+// behind an Arc and callers only hold the lock long enough to bump the reference count.
 lazy_static! {
-    static ref CORE_BPE_STORE: Arc<Mutex<HashMap<usize, CoreBPE>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref CORE_BPE_STORE: RwLock<HashMap<usize, Arc<CoreBPE>>> = RwLock::new(HashMap::new());
     static ref CORE_BPE_COUNTER: AtomicUsize = AtomicUsize::new(1);
 }
 
 // Helper function to get a CoreBPE instance by ID
-fn get_core_bpe_instance(id: usize) -> Option<CoreBPE> {
-    CORE_BPE_STORE.lock().unwrap().get(&id).cloned()
+fn get_core_bpe_instance(id: usize) -> Result<Arc<CoreBPE>, TiktokenError> {
+    CORE_BPE_STORE
+        .read()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or(TiktokenError::UnknownHandle(id))
 }
 
-// Function to create a new CoreBPE instance and return its ID
-#[ocaml::func]
-#[ocaml::sig("Value -> Value -> string -> int")]
-pub fn core_bpe_new(
-    encoder: Value,
-    special_tokens_encoder: Value,
-    pattern: String,
-) -> usize {
+// Helper function to check that a rank given as an OCaml int fits in a Rank. Rank::MAX is
+// excluded: the merge uses it to mark pairs that have no rank.
+fn rank_from_int(rank: ocaml::Int) -> Result<Rank, TiktokenError> {
+    Rank::try_from(rank)
+        .ok()
+        .filter(|&rank| rank != Rank::MAX)
+        .ok_or_else(|| {
+            TiktokenError::BadArgument(format!("rank {} is not in 0..{}", rank, Rank::MAX))
+        })
+}
+
+// Helper function to convert an OCaml (bytes * int) list into the encoder map
+fn encoder_from_value(encoder: Value) -> Result<HashMap<Vec<u8>, Rank>, TiktokenError> {
     let encoder_list: List<Value> = encoder.into();
     let encoder_vec: Vec<Value> = encoder_list.into_vec();
     let mut encoder_map: HashMap<Vec<u8>, Rank> = HashMap::new();
 
     for val in encoder_vec {
-        let tuple: (Vec<u8>, Rank) = val.into();
-        encoder_map.insert(tuple.0, tuple.1);
+        let tuple: (&[u8], ocaml::Int) = val.into();
+        encoder_map.insert(tuple.0.to_vec(), rank_from_int(tuple.1)?);
     }
+    Ok(encoder_map)
+}
 
+// Helper function to convert an OCaml (string * int) list into the special tokens map
+fn special_tokens_from_value(
+    special_tokens_encoder: Value,
+) -> Result<HashMap<String, Rank>, TiktokenError> {
     let special_tokens_list: List<Value> = special_tokens_encoder.into();
     let special_tokens_vec: Vec<Value> = special_tokens_list.into_vec();
     let mut special_tokens_map: HashMap<String, Rank> = HashMap::new();
 
     for val in special_tokens_vec {
-        let tuple: (String, Rank) = val.into();
-        special_tokens_map.insert(tuple.0, tuple.1);
+        let tuple: (&[u8], ocaml::Int) = val.into();
+        special_tokens_map.insert(text_from_bytes(tuple.0)?.to_string(), rank_from_int(tuple.1)?);
     }
+    Ok(special_tokens_map)
+}
 
-    let core_bpe = CoreBPE::new(encoder_map, special_tokens_map, &pattern).unwrap();
+// Helper function to store a CoreBPE instance and return its ID
+fn store_core_bpe_instance(core_bpe: CoreBPE) -> usize {
     let id = CORE_BPE_COUNTER.fetch_add(1, Ordering::SeqCst);
-    CORE_BPE_STORE.lock().unwrap().insert(id, core_bpe);
+    CORE_BPE_STORE.write().unwrap().insert(id, Arc::new(core_bpe));
+    id
+}
 
-    id // Return the ID to OCaml
+// Function to create a new CoreBPE instance and return its ID
+#[ocaml::func]
+#[ocaml::sig("(bytes * int) list -> (string * int) list -> string -> (int, tiktoken_error) result")]
+pub fn core_bpe_new(
+    encoder: Value,
+    special_tokens_encoder: Value,
+    pattern: &[u8],
+) -> Result<usize, TiktokenError> {
+    let encoder_map = encoder_from_value(encoder)?;
+    let special_tokens_map = special_tokens_from_value(special_tokens_encoder)?;
+
+    let core_bpe = CoreBPE::new(encoder_map, special_tokens_map, text_from_bytes(pattern)?)?;
+    Ok(store_core_bpe_instance(core_bpe)) // Return the ID to OCaml
 }
 
-// Function to encode text using CoreBPE by ID
+// Function to create a new CoreBPE instance from a .tiktoken rank file on disk
 #[ocaml::func]
-pub fn core_bpe_encode_ordinary(core_bpe_id: usize, text: String) -> Value {
-    let rt = Runtime::init(); // Initialize the OCaml runtime
+#[ocaml::sig("string -> (string * int) list -> string -> (int, tiktoken_error) result")]
+pub fn core_bpe_new_from_file(
+    path: &[u8],
+    special_tokens_encoder: Value,
+    pattern: &[u8],
+) -> Result<usize, TiktokenError> {
+    let special_tokens_map = special_tokens_from_value(special_tokens_encoder)?;
+    let core_bpe = CoreBPE::from_tiktoken_file(
+        text_from_bytes(path)?,
+        special_tokens_map,
+        text_from_bytes(pattern)?,
+    )?;
+    Ok(store_core_bpe_instance(core_bpe))
+}
 
-    if let Some(bpe) = get_core_bpe_instance(core_bpe_id) {
-        let result = bpe.encode_ordinary(&text);
-        result.to_value(&rt) // Convert the result to OCaml value using the Runtime
-    } else {
-        (-1isize).to_value(&rt) // Return error as isize if ID is invalid
+// Function to create a new CoreBPE instance from the contents of a .tiktoken rank file
+#[ocaml::func]
+#[ocaml::sig("string -> (string * int) list -> string -> (int, tiktoken_error) result")]
+pub fn core_bpe_new_from_string(
+    contents: &[u8],
+    special_tokens_encoder: Value,
+    pattern: &[u8],
+) -> Result<usize, TiktokenError> {
+    let special_tokens_map = special_tokens_from_value(special_tokens_encoder)?;
+    let core_bpe =
+        CoreBPE::from_tiktoken_bytes(contents, special_tokens_map, text_from_bytes(pattern)?)?;
+    Ok(store_core_bpe_instance(core_bpe))
+}
+
+// Helper function to locate the rank file of a standard encoding in the directory named by the
+// TIKTOKEN_RANKS_DIR environment variable
+fn default_rank_file_path(name: &str) -> String {
+    let dir = std::env::var("TIKTOKEN_RANKS_DIR").unwrap_or_else(|_| ".".to_string());
+    let path = std::path::Path::new(&dir).join(encodings::rank_file_name(name));
+    path.to_string_lossy().into_owned()
+}
+
+// Function to remove a CoreBPE instance from the store. Returns false if the ID was unknown or
+// already freed. IDs are never reused, so a freed ID behaves exactly like an unknown one and every
+// other core_bpe_* function returns Unknown_handle for it; calls already running on another
+// thread keep their own reference and finish normally.
+#[ocaml::func]
+#[ocaml::sig("int -> bool")]
+pub fn core_bpe_free(core_bpe_id: usize) -> bool {
+    CORE_BPE_STORE.write().unwrap().remove(&core_bpe_id).is_some()
+}
+
+// Function to check whether an ID refers to a live CoreBPE instance
+#[ocaml::func]
+#[ocaml::sig("int -> bool")]
+pub fn core_bpe_exists(core_bpe_id: usize) -> bool {
+    CORE_BPE_STORE.read().unwrap().contains_key(&core_bpe_id)
+}
+
+// Function to list the live IDs with the approximate memory usage of each instance in bytes
+#[ocaml::func]
+#[ocaml::sig("unit -> (int * int) list")]
+pub fn core_bpe_list_ids() -> LinkedList<(usize, usize)> {
+    let store = CORE_BPE_STORE.read().unwrap();
+    let mut ids: Vec<(usize, usize)> = store
+        .iter()
+        .map(|(id, bpe)| (*id, bpe.memory_usage()))
+        .collect();
+    ids.sort_unstable();
+    ids.into_iter().collect()
+}
+
+// Function to list the names accepted by get_encoding
+#[ocaml::func]
+#[ocaml::sig("unit -> string array")]
+pub fn list_encoding_names() -> Vec<String> {
+    encodings::ENCODINGS
+        .iter()
+        .map(|spec| spec.name.to_string())
+        .collect()
+}
+
+// Function to look up the name of the encoding used by a model, e.g. "gpt-4o" -> "o200k_base"
+#[ocaml::func]
+#[ocaml::sig("string -> string option")]
+pub fn encoding_name_for_model(model: &[u8]) -> Option<String> {
+    let model = text_from_bytes(model).ok()?;
+    encodings::encoding_name_for_model(model).map(|name| name.to_string())
+}
+
+// Function to look up the regex pattern of a standard encoding, e.g. to train a vocabulary that
+// splits text the same way
+#[ocaml::func]
+#[ocaml::sig("string -> string option")]
+pub fn encoding_pattern(name: &[u8]) -> Option<String> {
+    let name = text_from_bytes(name).ok()?;
+    encodings::encoding_spec(name).map(|spec| spec.pattern.to_string())
+}
+
+// Helper function to convert an OCaml string list of allowed special tokens into a set
+fn allowed_special_from_list<'a>(
+    allowed_special: &[&'a [u8]],
+) -> Result<HashSet<&'a str>, TiktokenError> {
+    allowed_special.iter().map(|s| text_from_bytes(s)).collect()
+}
+
+// Helper functions to pass tokens to and from OCaml as `int`. ocaml-rs converts a Rank to and from
+// a boxed int32, which is not what the signatures declare.
+fn tokens_to_ints(tokens: Vec<Rank>) -> Vec<ocaml::Int> {
+    tokens.into_iter().map(|token| token as ocaml::Int).collect()
+}
+
+fn token_from_int(token: ocaml::Int) -> Result<Rank, TiktokenError> {
+    Rank::try_from(token).map_err(|_| TiktokenError::UnknownToken(token))
+}
+
+// Ints outside the rank range become Rank::MAX, which is never a token (see `rank_from_int`), so
+// they decode like any other unknown token: reported in order, or replaced by lossy decoding.
+fn tokens_from_ints(tokens: &[ocaml::Int]) -> Vec<Rank> {
+    tokens
+        .iter()
+        .map(|&token| Rank::try_from(token).unwrap_or(Rank::MAX))
+        .collect()
+}
+
+// Reports an unknown token from `tokens_from_ints` with the int it was given.
+fn unknown_token_as_given(tokens: &[ocaml::Int], error: TiktokenError) -> TiktokenError {
+    match error {
+        TiktokenError::UnknownTokenAt(_, i) => TiktokenError::UnknownTokenAt(tokens[i], i),
+        error => error,
     }
 }
 
+// Helper function to copy OCaml strings out of the heap so they can be used without the runtime lock
+fn texts_from_list(texts: List<&[u8]>) -> Result<Vec<String>, TiktokenError> {
+    texts
+        .into_vec()
+        .into_iter()
+        .map(|text| text_from_bytes(text).map(str::to_string))
+        .collect()
+}
+
+// Helper function to resolve OCaml `allowed_special` / `disallowed_special` selections against an
+// encoding. `All` for disallowed_special means every special token that is not allowed.
+fn resolve_special_tokens<'a>(
+    bpe: &'a CoreBPE,
+    allowed_special: SpecialTokens<'a>,
+    disallowed_special: SpecialTokens<'a>,
+) -> Result<(HashSet<&'a str>, HashSet<&'a str>), TiktokenError> {
+    let all = || bpe.special_tokens_encoder.keys().map(|s| s.as_str());
+    let allowed_special: HashSet<&str> = match allowed_special {
+        SpecialTokens::All => all().collect(),
+        SpecialTokens::Tokens(tokens) => allowed_special_from_list(&tokens.into_vec())?,
+    };
+    let disallowed_special: HashSet<&str> = match disallowed_special {
+        SpecialTokens::All => all().filter(|s| !allowed_special.contains(s)).collect(),
+        SpecialTokens::Tokens(tokens) => allowed_special_from_list(&tokens.into_vec())?,
+    };
+    Ok((allowed_special, disallowed_special))
+}
+
+// Function to encode text using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> string -> (int array, tiktoken_error) result")]
+pub fn core_bpe_encode_ordinary(core_bpe_id: usize, text: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = get_core_bpe_instance(core_bpe_id)?;
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| bpe.encode_ordinary(text)).map(tokens_to_ints)
+}
+
+// Function to encode text, allowing the listed special tokens, using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> string -> string list -> (int array, tiktoken_error) result")]
+pub fn core_bpe_encode(
+    core_bpe_id: usize,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = get_core_bpe_instance(core_bpe_id)?;
+    let allowed_special = texts_from_list(allowed_special)?;
+    with_input_unlocked(gc, text_from_bytes(text)?, |text| {
+        bpe.encode(text, allowed_special.iter().map(String::as_str).collect())
+    })
+    .map(tokens_to_ints)
+}
+
+// Function to encode raw bytes (which may not be valid UTF-8) using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> bytes -> (int array, tiktoken_error) result")]
+pub fn core_bpe_encode_bytes(core_bpe_id: usize, bytes: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    let bpe = get_core_bpe_instance(core_bpe_id)?;
+    with_input_unlocked(gc, bytes, |bytes| bpe.encode_bytes(bytes)).map(tokens_to_ints)
+}
+
+// Function to encode text and return the stable tokens plus the possible completions of the
+// unstable last piece, using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> string -> string list -> (int array * int array list, tiktoken_error) result")]
+pub fn core_bpe_encode_with_unstable(
+    core_bpe_id: usize,
+    text: &[u8],
+    allowed_special: List<&[u8]>,
+) -> Result<(Vec<ocaml::Int>, LinkedList<Vec<ocaml::Int>>), TiktokenError> {
+    let bpe = get_core_bpe_instance(core_bpe_id)?;
+    let allowed_special = allowed_special.into_vec();
+    let (tokens, completions) =
+        bpe.encode_with_unstable(text_from_bytes(text)?, allowed_special_from_list(&allowed_special)?)?;
+    Ok((tokens_to_ints(tokens), completions.into_iter().map(tokens_to_ints).collect()))
+}
+
+// Function to look up the token of a single piece (ordinary or special) using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> bytes -> (int, tiktoken_error) result")]
+pub fn core_bpe_encode_single_token(core_bpe_id: usize, piece: &[u8]) -> Result<ocaml::Int, TiktokenError> {
+    Ok(get_core_bpe_instance(core_bpe_id)?.encode_single_token(piece)? as ocaml::Int)
+}
+
+// Function to byte-pair encode a single piece without applying the regex, using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> bytes -> (int array, tiktoken_error) result")]
+pub fn core_bpe_encode_single_piece(core_bpe_id: usize, piece: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    get_core_bpe_instance(core_bpe_id)?.encode_single_piece(piece).map(tokens_to_ints)
+}
+
 // Function to decode tokens using CoreBPE by ID
 #[ocaml::func]
-pub fn core_bpe_decode_bytes(core_bpe_id: usize, tokens: Value) -> Value {
-    let rt = Runtime::init(); // Initialize the OCaml runtime
+#[ocaml::sig("int -> int array -> (bytes, tiktoken_error) result")]
+pub fn core_bpe_decode_bytes(core_bpe_id: usize, tokens: Vec<ocaml::Int>) -> Result<Value, TiktokenError> {
+    let bpe = get_core_bpe_instance(core_bpe_id)?;
+    let ranks = tokens_from_ints(&tokens);
+    let result = decode_maybe_unlocked(gc, ranks.len(), || bpe.decode_bytes(&ranks)) // Decode the bytes using CoreBPE
+        .map_err(|e| unknown_token_as_given(&tokens, e))?;
+    Ok(result.as_slice().to_value(gc)) // Convert the result (Vec<u8>) to OCaml bytes
+}
 
-    if let Some(bpe) = get_core_bpe_instance(core_bpe_id) {
-        let tokens: Vec<Rank> = tokens.into();  // Convert OCaml Value to Vec<Rank>
-        let result = bpe.decode_bytes(tokens);  // Decode the bytes using CoreBPE
-        result.to_value(&rt)  // Convert the result (Vec<u8>) to an OCaml value
-    } else {
-        (-1isize).to_value(&rt)  // Return an error as an OCaml integer if ID is invalid
+// Function to get the bytes of a single (ordinary or special) token using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> int -> (bytes, tiktoken_error) result")]
+pub fn core_bpe_decode_single_token_bytes(core_bpe_id: usize, token: ocaml::Int) -> Result<Value, TiktokenError> {
+    let bytes = get_core_bpe_instance(core_bpe_id)?.decode_single_token_bytes(token_from_int(token)?)?;
+    Ok(bytes.as_slice().to_value(gc))
+}
+
+// Function to get the bytes of every ordinary token, sorted, using CoreBPE by ID
+#[ocaml::func]
+#[ocaml::sig("int -> (bytes array, tiktoken_error) result")]
+pub fn core_bpe_token_byte_values(core_bpe_id: usize) -> Result<Vec<Value>, TiktokenError> {
+    let bpe = get_core_bpe_instance(core_bpe_id)?;
+    Ok(bpe
+        .token_byte_values()
+        .iter()
+        .map(|bytes| bytes.as_slice().to_value(gc))
+        .collect())
+}
+
+// Fails if `text` contains any of `disallowed_special`, reporting the leftmost occurrence like
+// searching with a regex of the disallowed tokens.
+fn check_disallowed_special(
+    text: &str,
+    disallowed_special: &HashSet<&str>,
+) -> Result<(), TiktokenError> {
+    let first_disallowed = disallowed_special
+        .iter()
+        .filter(|token| !token.is_empty())
+        .filter_map(|token| text.find(token).map(|start| (start, *token)))
+        .min();
+    match first_disallowed {
+        Some((_, token)) => Err(TiktokenError::DisallowedSpecialToken(token.to_string())),
+        None => Ok(()),
     }
 }
 
+// Maps `f` over `items` on up to `num_threads` scoped threads (0 for one per available core),
+// keeping results in input order. Threads pull the next item from a shared counter, so a few long
+// documents do not leave the other threads idle. A panic in a thread is caught there and re-raised
+// on the calling thread once every thread is done.
+fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    num_threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let num_threads = match num_threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(MAX_NUM_THREADS)
+    .min(items.len());
+    if num_threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let work = || {
+        let mut done = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            if i >= items.len() {
+                return done;
+            }
+            done.push((i, f(&items[i])));
+        }
+    };
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    let mut panic = None;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..num_threads)
+            .map(|_| scope.spawn(|| catch_panic(work)))
+            .collect();
+        for worker in workers {
+            // `catch_panic` does not let the thread itself panic.
+            match worker.join().unwrap() {
+                Ok(done) => {
+                    for (i, result) in done {
+                        results[i] = Some(result);
+                    }
+                }
+                Err(msg) => panic = Some(msg),
+            }
+        }
+    });
+    if let Some(msg) = panic {
+        // Raised here, where the ocaml-rs hook or an enclosing `catch_panic` handles it.
+        panic!("{}", msg);
+    }
+    results.into_iter().map(|result| result.unwrap()).collect()
+}
+
+thread_local! {
+    // Set while `catch_panic` runs, see there.
+    static CATCHING_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Runs `f` and returns the message of a panic in it instead of unwinding. The panic hook of
+// ocaml-rs raises an OCaml exception right where the panic happens, which is only valid on a
+// thread holding the runtime lock, so it is wrapped (once) by a hook that skips it meanwhile.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    static WRAP_HOOK: Once = Once::new();
+    WRAP_HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if !CATCHING_PANICS.with(Cell::get) {
+                hook(info);
+            }
+        }));
+    });
+
+    let catching = CATCHING_PANICS.with(|c| c.replace(true));
+    let result = std::panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING_PANICS.with(|c| c.set(catching));
+    result.map_err(|payload| {
+        payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Rust panic".to_string())
+    })
+}
+
+// Runs `f` with the OCaml runtime lock released, so other OCaml threads (and domains on OCaml 5)
+// keep running during CPU-bound work. `f` must not touch OCaml values: the GC may move or free
+// them meanwhile, so inputs have to be copied out first. A panic in `f` is caught and, once the
+// lock is reacquired, raised as `Failure` like a panic anywhere else in the bindings.
+fn without_runtime_lock<T>(gc: &ocaml::Runtime, f: impl FnOnce() -> T) -> T {
+    gc.enter_blocking_section();
+    let result = catch_panic(f);
+    gc.leave_blocking_section();
+    result.unwrap_or_else(|msg| ocaml::Error::raise_failure(&msg))
+}
+
+// Runs `f` on `input`, first copying it out and releasing the runtime lock if it is at least
+// RELEASE_RUNTIME_BYTES long. `f` must only use values that stay valid without the lock (an Arc'd
+// CoreBPE, owned copies), never memory borrowed from the OCaml heap.
+fn with_input_unlocked<I, T>(gc: &ocaml::Runtime, input: &I, f: impl FnOnce(&I) -> T) -> T
+where
+    I: ?Sized + ToOwned + AsRef<[u8]>,
+{
+    if input.as_ref().len() < RELEASE_RUNTIME_BYTES {
+        return f(input);
+    }
+    let input = input.to_owned();
+    without_runtime_lock(gc, || f(input.borrow()))
+}
+
+// Decoding counterpart of `with_input_unlocked`; token arrays are already copied by the conversion
+// from OCaml.
+fn decode_maybe_unlocked<T>(gc: &ocaml::Runtime, num_tokens: usize, f: impl FnOnce() -> T) -> T {
+    if num_tokens < RELEASE_RUNTIME_TOKENS {
+        return f();
+    }
+    without_runtime_lock(gc, f)
+}
+
+// Copies tokens into a new `(int32, int32_elt, c_layout) Array1.t`. The buffer is allocated by
+// the OCaml runtime and freed with the bigarray (`Array1::create` marks its buffer as external,
+// so it would leak).
+fn tokens_to_bigarray(tokens: &[Rank]) -> Array1<i32> {
+    use ocaml::sys::bigarray::{caml_ba_alloc_dims, Kind, Managed};
+
+    let mut array: Array1<i32> = unsafe {
+        Array1::from_value(Value::new(caml_ba_alloc_dims(
+            Kind::INT32 as i32 | Managed::MANAGED as i32,
+            1,
+            std::ptr::null_mut(),
+            tokens.len() as ocaml::Int,
+        )))
+    };
+    // An empty bigarray may have a null data pointer, which must not become a slice.
+    if !tokens.is_empty() {
+        for (dst, &token) in array.data_mut().iter_mut().zip(tokens) {
+            *dst = token as i32;
+        }
+    }
+    array
+}
+
+// Views an int32 Bigarray of tokens as ranks without copying; ranks are stored as their int32
+// bit pattern. A negative value would read as a large rank, so the view stops before the first one,
+// which is returned with its index to be reported as the caller gave it.
+fn tokens_of_bigarray(tokens: &Array1<i32>) -> (&[Rank], Option<(ocaml::Int, usize)>) {
+    if tokens.is_empty() {
+        return (&[], None);
+    }
+    let data = tokens.data();
+    let negative = data.iter().position(|&token| token < 0);
+    let len = negative.unwrap_or(data.len());
+    let ranks = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const Rank, len) };
+    (ranks, negative.map(|i| (data[i] as ocaml::Int, i)))
+}
+
 fn hash_current_thread() -> usize {
     use std::num::NonZeroU64;
     use std::thread;
@@ -504,3 +1530,116 @@ fn hash_current_thread() -> usize {
     };
     u64::from(x) as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift64, so the test needs no dependency and fails the same way every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn byte_pair_merge_large_matches_small() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..20 {
+            // A few letters, so that random tokens overlap and the merges interact.
+            let alphabet = 2 + rng.below(5);
+            let letter = |rng: &mut Rng| b'a' + rng.below(alphabet) as u8;
+            let mut tokens: Vec<Vec<u8>> = (0..alphabet).map(|i| vec![b'a' + i as u8]).collect();
+            for _ in 0..rng.below(200) {
+                let len = 2 + rng.below(6);
+                tokens.push((0..len).map(|_| letter(&mut rng)).collect());
+            }
+            let mut ranks: HashMap<Vec<u8>, Rank> = HashMap::new();
+            for token in tokens {
+                let rank = rng.below(1 << 20) as Rank;
+                ranks.entry(token).or_insert(rank);
+            }
+
+            let threshold = LARGE_PIECE_THRESHOLD;
+            for len in [1, 2, 3, 10, 100, threshold - 1, threshold, threshold + 1, 3 * threshold] {
+                let piece: Vec<u8> = (0..len).map(|_| letter(&mut rng)).collect();
+                assert_eq!(
+                    _byte_pair_merge_large(&ranks, &piece),
+                    _byte_pair_merge_small(&ranks, &piece),
+                    "piece {:?}",
+                    String::from_utf8_lossy(&piece)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_map_reports_a_worker_panic_to_the_caller() {
+        let items: Vec<usize> = (0..100).collect();
+        let result = catch_panic(|| {
+            parallel_map(&items, 4, |&i| if i == 57 { panic!("item {}", i) } else { i })
+        });
+        assert!(result.unwrap_err().contains("item 57"));
+        // Nothing is left broken for the next call.
+        assert_eq!(parallel_map(&items, 4, |&i| i * 2), (0..200).step_by(2).collect::<Vec<_>>());
+    }
+
+    // Benchmark for the store, run with `cargo test --release -- --ignored`. A short encode
+    // through the `Arc` looked up in the store must cost a small fraction of one that first copies
+    // the rank tables, as the store did on every call before instances were shared.
+    #[test]
+    #[ignore]
+    fn bench_store_lookup_against_copy() {
+        // Every single byte, every byte pair and every three-letter lowercase word, as in
+        // bench/bench.ml
+        let mut encoder: HashMap<Vec<u8>, Rank> = HashMap::new();
+        let mut add = |token: Vec<u8>| {
+            let rank = encoder.len() as Rank;
+            encoder.insert(token, rank);
+        };
+        (0..=255).for_each(|a| add(vec![a]));
+        (0..=255).for_each(|a| (0..=255).for_each(|b| add(vec![a, b])));
+        for a in b'a'..=b'z' {
+            for b in b'a'..=b'z' {
+                (b'a'..=b'z').for_each(|c| add(vec![a, b, c]));
+            }
+        }
+        let vocab_size = encoder.len();
+        let special_tokens = HashMap::from([("<|endoftext|>".to_string(), vocab_size as Rank)]);
+        let bpe = CoreBPE::new(encoder, special_tokens, "\\w+| ?[^\\s\\w]+|\\s+").unwrap();
+        let id = store_core_bpe_instance(bpe);
+
+        let per_call = |iterations: u32, f: &dyn Fn() -> Vec<Rank>| {
+            let start = std::time::Instant::now();
+            for _ in 0..iterations {
+                std::hint::black_box(f());
+            }
+            start.elapsed() / iterations
+        };
+        let lookup = per_call(10_000, &|| {
+            get_core_bpe_instance(id).unwrap().encode_ordinary("hello world").unwrap()
+        });
+        let copy = per_call(20, &|| {
+            let shared = get_core_bpe_instance(id).unwrap();
+            let bpe = CoreBPE {
+                encoder: Arc::new(HashMap::clone(&shared.encoder)),
+                decoder: Arc::new(HashMap::clone(&shared.decoder)),
+                sorted_token_bytes: Arc::new(Vec::clone(&shared.sorted_token_bytes)),
+                ..CoreBPE::clone(&shared)
+            };
+            bpe.encode_ordinary("hello world").unwrap()
+        });
+        CORE_BPE_STORE.write().unwrap().remove(&id);
+        assert!(
+            lookup * 100 < copy,
+            "lookup {:?} per call, copy {:?} per call",
+            lookup,
+            copy
+        );
+    }
+}
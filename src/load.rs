@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::{Rank, TiktokenError};
+
+// Decodes a single base64 character into its 6-bit value.
+fn base64_value(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Standard (padded) base64 encoding, the inverse of `base64_decode`.
+pub fn base64_encode(input: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let mut bytes = [0; 4];
+        bytes[1..1 + chunk.len()].copy_from_slice(chunk);
+        let acc = u32::from_be_bytes(bytes);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(BASE64_ALPHABET[(acc >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                ret.push(b'=');
+            }
+        }
+    }
+    ret
+}
+
+// Standard (padded) base64 decoding, which is what .tiktoken files use for tokens.
+pub fn base64_decode(input: &[u8]) -> Result<Vec<u8>, String> {
+    if !input.len().is_multiple_of(4) {
+        return Err(format!("Invalid base64 length {}", input.len()));
+    }
+    let mut ret = Vec::with_capacity(input.len() / 4 * 3);
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let is_last = (i + 1) * 4 == input.len();
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !is_last) {
+            return Err("Invalid base64 padding".to_string());
+        }
+        let mut acc: u32 = 0;
+        for &c in &chunk[..4 - padding] {
+            let v = base64_value(c).ok_or_else(|| format!("Invalid base64 character {:?}", c as char))?;
+            acc = (acc << 6) | v as u32;
+        }
+        acc <<= 6 * padding as u32;
+        let bytes = acc.to_be_bytes();
+        ret.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Ok(ret)
+}
+
+// Parses the contents of a .tiktoken rank file: one `<base64 token> <rank>` pair per line.
+// Blank lines are skipped; duplicate tokens and duplicate ranks are rejected since `CoreBPE`
+// needs the mapping to be invertible.
+pub fn load_tiktoken_bpe(contents: &[u8]) -> Result<HashMap<Vec<u8>, Rank>, TiktokenError> {
+    let invalid = |lineno: usize, msg: &str| {
+        TiktokenError::InvalidRankFile(format!("line {}: {}", lineno + 1, msg))
+    };
+    let mut encoder = HashMap::new();
+    let mut seen_ranks = HashSet::new();
+    for (lineno, line) in contents.split(|&b| b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split(|&b| b == b' ');
+        let (token, rank) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(token), Some(rank), None) => (token, rank),
+            _ => return Err(invalid(lineno, "expected `<base64 token> <rank>`")),
+        };
+        let token = base64_decode(token).map_err(|e| invalid(lineno, &e))?;
+        let rank: Rank = std::str::from_utf8(rank)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&rank| rank != Rank::MAX)
+            .ok_or_else(|| invalid(lineno, "invalid rank"))?;
+        if !seen_ranks.insert(rank) {
+            return Err(TiktokenError::DuplicateRank(rank as ocaml::Int));
+        }
+        if encoder.insert(token, rank).is_some() {
+            return Err(invalid(lineno, "duplicate token"));
+        }
+    }
+    Ok(encoder)
+}
+
+pub fn load_tiktoken_bpe_file(
+    path: impl AsRef<Path>,
+) -> Result<HashMap<Vec<u8>, Rank>, TiktokenError> {
+    let path = path.as_ref();
+    let contents = std::fs::read(path)
+        .map_err(|e| TiktokenError::IoError(format!("{}: {}", path.display(), e)))?;
+    load_tiktoken_bpe(&contents)
+}
+
+// Formats an encoder as a .tiktoken rank file, one line per token in rank order.
+pub fn dump_tiktoken_bpe(encoder: &HashMap<Vec<u8>, Rank>) -> Vec<u8> {
+    let mut tokens: Vec<(&Vec<u8>, Rank)> = encoder.iter().map(|(k, &v)| (k, v)).collect();
+    tokens.sort_unstable_by_key(|&(_, rank)| rank);
+    let mut ret = Vec::new();
+    for (token, rank) in tokens {
+        ret.extend(base64_encode(token));
+        ret.extend(format!(" {}\n", rank).as_bytes());
+    }
+    ret
+}
+
+pub fn write_tiktoken_bpe_file(
+    path: impl AsRef<Path>,
+    encoder: &HashMap<Vec<u8>, Rank>,
+) -> Result<(), TiktokenError> {
+    let path = path.as_ref();
+    std::fs::write(path, dump_tiktoken_bpe(encoder))
+        .map_err(|e| TiktokenError::IoError(format!("{}: {}", path.display(), e)))
+}
@@ -2,6 +2,95 @@
 
 open! Bigarray
 
+(* file: chunk.rs *)
+
+type chunk_boundary = Any | Whitespace | Sentence
+type chunk_options = { max_tokens: int; overlap: int; boundary: chunk_boundary }
+
+(* file: encodings.rs *)
+
+type special_tokens = All | Tokens of string list
+
+(* file: error.rs *)
+
+type tiktoken_error = Invalid_pattern of string | Invalid_rank_file of string | Io_error of string | Duplicate_rank of int | Unknown_token of int | Unknown_token_at of int * int | Unknown_piece of string | Unknown_handle of int | Disallowed_special_token of string | Invalid_utf8 of int | Unknown_encoding of string | Unknown_model of string | Vocab_size_mismatch of int * int | Bad_argument of string | Invalid_snapshot of string
+type decode_errors = Strict | Replace | Ignore
+external string_of_error: tiktoken_error -> string = "string_of_error"
+
+(* file: handle.rs *)
+
+type encoding
+external encoding_live_handles: unit -> int = "encoding_live_handles"
+external encoding_new: (bytes * int) list -> (string * int) list -> string -> (encoding, tiktoken_error) result = "encoding_new"
+external encoding_new_from_file: string -> (string * int) list -> string -> (encoding, tiktoken_error) result = "encoding_new_from_file"
+external encoding_new_from_string: string -> (string * int) list -> string -> (encoding, tiktoken_error) result = "encoding_new_from_string"
+external encoding_load_snapshot: string -> (encoding, tiktoken_error) result = "encoding_load_snapshot"
+external encoding_save_snapshot: encoding -> string -> (unit, tiktoken_error) result = "encoding_save_snapshot"
+external get_encoding: string -> (encoding, tiktoken_error) result = "get_encoding"
+external get_encoding_from_file: string -> string -> (encoding, tiktoken_error) result = "get_encoding_from_file"
+external encoding_for_model: string -> (encoding, tiktoken_error) result = "encoding_for_model"
+external encoding_extend: encoding -> (string * int) list -> string list -> string option -> (encoding, tiktoken_error) result = "encoding_extend"
+external encoding_encode_ordinary: encoding -> string -> (int array, tiktoken_error) result = "encoding_encode_ordinary"
+external encoding_encode: encoding -> string -> string list -> (int array, tiktoken_error) result = "encoding_encode"
+external encoding_encode_with_special: encoding -> string -> special_tokens -> special_tokens -> (int array, tiktoken_error) result = "encoding_encode_with_special"
+external encoding_encode_with_spans: encoding -> string -> string list -> (int array * (int * int) array, tiktoken_error) result = "encoding_encode_with_spans"
+external encoding_encode_bytes: encoding -> bytes -> (int array, tiktoken_error) result = "encoding_encode_bytes"
+external encoding_encode_with_unstable: encoding -> string -> string list -> (int array * int array list, tiktoken_error) result = "encoding_encode_with_unstable"
+external encoding_encode_single_token: encoding -> bytes -> (int, tiktoken_error) result = "encoding_encode_single_token"
+external encoding_encode_single_piece: encoding -> bytes -> (int array, tiktoken_error) result = "encoding_encode_single_piece"
+external encoding_decode_bytes: encoding -> int array -> (bytes, tiktoken_error) result = "encoding_decode_bytes"
+external encoding_decode: encoding -> int array -> decode_errors -> (string, tiktoken_error) result = "encoding_decode"
+external encoding_decode_bytes_lossy: encoding -> int array -> bytes -> bytes = "encoding_decode_bytes_lossy"
+external encoding_encode_ordinary_bigarray: encoding -> string -> ((int32, int32_elt, c_layout) Array1.t, tiktoken_error) result = "encoding_encode_ordinary_bigarray"
+external encoding_encode_bigarray: encoding -> string -> string list -> ((int32, int32_elt, c_layout) Array1.t, tiktoken_error) result = "encoding_encode_bigarray"
+external encoding_decode_bytes_bigarray: encoding -> (int32, int32_elt, c_layout) Array1.t -> (bytes, tiktoken_error) result = "encoding_decode_bytes_bigarray"
+external encoding_decode_with_offsets: encoding -> int array -> (string * int array * int array, tiktoken_error) result = "encoding_decode_with_offsets"
+external encoding_decode_single_token_bytes: encoding -> int -> (bytes, tiktoken_error) result = "encoding_decode_single_token_bytes"
+external encoding_token_byte_values: encoding -> bytes array = "encoding_token_byte_values"
+external encoding_encode_ordinary_batch: encoding -> string list -> int -> (int array list, tiktoken_error) result = "encoding_encode_ordinary_batch"
+external encoding_encode_batch: encoding -> string list -> string list -> int -> (int array list, tiktoken_error) result = "encoding_encode_batch"
+external encoding_decode_batch: encoding -> int array list -> int -> (bytes list, tiktoken_error) result = "encoding_decode_batch"
+external encoding_count_tokens_ordinary: encoding -> string -> (int, tiktoken_error) result = "encoding_count_tokens_ordinary"
+external encoding_count_tokens: encoding -> string -> string list -> (int, tiktoken_error) result = "encoding_count_tokens"
+external encoding_count_tokens_batch: encoding -> string list -> string list -> int -> (int array, tiktoken_error) result = "encoding_count_tokens_batch"
+external encoding_encode_truncated: encoding -> string -> string list -> int -> (int array * int, tiktoken_error) result = "encoding_encode_truncated"
+external encoding_encode_truncated_tail: encoding -> string -> string list -> int -> (int array * int, tiktoken_error) result = "encoding_encode_truncated_tail"
+external encoding_chunk: encoding -> string -> chunk_options -> special_tokens -> special_tokens -> ((int * int * int array) list, tiktoken_error) result = "encoding_chunk"
+
 (* file: lib.rs *)
 
-external core_bpe_new: Value -> Value -> string -> int = "core_bpe_new"
+external core_bpe_new: (bytes * int) list -> (string * int) list -> string -> (int, tiktoken_error) result = "core_bpe_new"
+external core_bpe_new_from_file: string -> (string * int) list -> string -> (int, tiktoken_error) result = "core_bpe_new_from_file"
+external core_bpe_new_from_string: string -> (string * int) list -> string -> (int, tiktoken_error) result = "core_bpe_new_from_string"
+external core_bpe_free: int -> bool = "core_bpe_free"
+external core_bpe_exists: int -> bool = "core_bpe_exists"
+external core_bpe_list_ids: unit -> (int * int) list = "core_bpe_list_ids"
+external list_encoding_names: unit -> string array = "list_encoding_names"
+external encoding_name_for_model: string -> string option = "encoding_name_for_model"
+external encoding_pattern: string -> string option = "encoding_pattern"
+external core_bpe_encode_ordinary: int -> string -> (int array, tiktoken_error) result = "core_bpe_encode_ordinary"
+external core_bpe_encode: int -> string -> string list -> (int array, tiktoken_error) result = "core_bpe_encode"
+external core_bpe_encode_bytes: int -> bytes -> (int array, tiktoken_error) result = "core_bpe_encode_bytes"
+external core_bpe_encode_with_unstable: int -> string -> string list -> (int array * int array list, tiktoken_error) result = "core_bpe_encode_with_unstable"
+external core_bpe_encode_single_token: int -> bytes -> (int, tiktoken_error) result = "core_bpe_encode_single_token"
+external core_bpe_encode_single_piece: int -> bytes -> (int array, tiktoken_error) result = "core_bpe_encode_single_piece"
+external core_bpe_decode_bytes: int -> int array -> (bytes, tiktoken_error) result = "core_bpe_decode_bytes"
+external core_bpe_decode_single_token_bytes: int -> int -> (bytes, tiktoken_error) result = "core_bpe_decode_single_token_bytes"
+external core_bpe_token_byte_values: int -> (bytes array, tiktoken_error) result = "core_bpe_token_byte_values"
+
+(* file: stream.rs *)
+
+type stream_encoder
+type decode_event = Text of string | Special_token of string
+type stream_decoder
+external encoding_stream_encoder: encoding -> string list -> (stream_encoder, tiktoken_error) result = "encoding_stream_encoder"
+external stream_encoder_push: stream_encoder -> string -> (int array, tiktoken_error) result = "stream_encoder_push"
+external stream_encoder_finish: stream_encoder -> (int array, tiktoken_error) result = "stream_encoder_finish"
+external encoding_stream_decoder: encoding -> decode_errors -> stream_decoder = "encoding_stream_decoder"
+external stream_decoder_push: stream_decoder -> int array -> (decode_event list, tiktoken_error) result = "stream_decoder_push"
+external stream_decoder_finish: stream_decoder -> (decode_event list, tiktoken_error) result = "stream_decoder_finish"
+
+(* file: train.rs *)
+
+external train_bpe: string list -> string -> int -> ((bytes * int) list, tiktoken_error) result = "train_bpe"
+external write_tiktoken_file: (bytes * int) list -> string -> (unit, tiktoken_error) result = "write_tiktoken_file"
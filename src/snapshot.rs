@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use fancy_regex::Regex;
+
+use crate::{special_tokens_regex, CoreBPE, Rank, TiktokenError, MAX_NUM_THREADS};
+
+// A snapshot stores a built CoreBPE so that loading skips the base64 parsing and the sort of the
+// token index. Integers are little-endian u32 and byte strings are a length followed by the bytes:
+//
+//   magic | version | pattern | special token count | (token, rank)... | token count |
+//   (token, rank)... in byte order | FNV-1a checksum (u64) of everything before it
+const MAGIC: &[u8; 8] = b"TKTKSNAP";
+const VERSION: u32 = 1;
+
+fn invalid(msg: &str) -> TiktokenError {
+    TiktokenError::InvalidSnapshot(msg.to_string())
+}
+
+// FNV-1a, which is enough to catch truncated or corrupted files.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], TiktokenError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        let ret = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(ret)
+    }
+
+    fn u32(&mut self) -> Result<u32, TiktokenError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    // Rank::MAX is never a token, as in rank files.
+    fn rank(&mut self) -> Result<Rank, TiktokenError> {
+        Some(self.u32()?)
+            .filter(|&rank| rank != Rank::MAX)
+            .ok_or_else(|| invalid("rank out of range"))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], TiktokenError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // Capacity to reserve for `count` entries, which can be no more than the remaining data
+    // holds, whatever the count says.
+    fn capacity(&self, count: u32) -> usize {
+        (count as usize).min((self.bytes.len() - self.pos) / 8)
+    }
+}
+
+impl CoreBPE {
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        write_bytes(&mut out, self._get_tl_regex().as_str().as_bytes());
+
+        let mut special_tokens: Vec<(&String, &Rank)> =
+            self.special_tokens_encoder.iter().collect();
+        special_tokens.sort_unstable_by_key(|&(_, rank)| rank);
+        out.extend_from_slice(&(special_tokens.len() as u32).to_le_bytes());
+        for (token, rank) in special_tokens {
+            write_bytes(&mut out, token.as_bytes());
+            out.extend_from_slice(&rank.to_le_bytes());
+        }
+
+        out.extend_from_slice(&(self.sorted_token_bytes.len() as u32).to_le_bytes());
+        for token in self.sorted_token_bytes.iter() {
+            write_bytes(&mut out, token);
+            out.extend_from_slice(&self.encoder[token].to_le_bytes());
+        }
+
+        let checksum = checksum(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    pub fn from_snapshot(data: &[u8]) -> Result<Self, TiktokenError> {
+        if data.len() < MAGIC.len() + 4 + 8 || !data.starts_with(MAGIC) {
+            return Err(invalid("not a snapshot"));
+        }
+        let (body, stored_checksum) = data.split_at(data.len() - 8);
+        let mut reader = Reader { bytes: body, pos: MAGIC.len() };
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        if checksum(body).to_le_bytes() != stored_checksum {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let pattern = std::str::from_utf8(reader.bytes()?)
+            .map_err(|_| invalid("pattern is not valid UTF-8"))?;
+
+        let count = reader.u32()?;
+        let mut special_tokens_encoder = HashMap::with_capacity(reader.capacity(count));
+        let mut special_tokens_decoder = HashMap::with_capacity(reader.capacity(count));
+        for _ in 0..count {
+            let token = reader.bytes()?;
+            let rank = reader.rank()?;
+            let name = std::str::from_utf8(token)
+                .map_err(|_| invalid("special token is not valid UTF-8"))?;
+            if special_tokens_decoder.insert(rank, token.to_vec()).is_some() {
+                return Err(TiktokenError::DuplicateRank(rank as ocaml::Int));
+            }
+            special_tokens_encoder.insert(name.to_string(), rank);
+        }
+
+        let count = reader.u32()?;
+        let mut encoder = HashMap::with_capacity(reader.capacity(count));
+        let mut decoder = HashMap::with_capacity(reader.capacity(count));
+        let mut sorted_token_bytes: Vec<Vec<u8>> = Vec::with_capacity(reader.capacity(count));
+        for _ in 0..count {
+            let token = reader.bytes()?;
+            let rank = reader.rank()?;
+            // Strictly increasing, so the index needs no sort and tokens are unique.
+            if sorted_token_bytes.last().is_some_and(|last| last.as_slice() >= token) {
+                return Err(invalid("tokens out of order"));
+            }
+            if decoder.insert(rank, token.to_vec()).is_some() {
+                return Err(TiktokenError::DuplicateRank(rank as ocaml::Int));
+            }
+            encoder.insert(token.to_vec(), rank);
+            sorted_token_bytes.push(token.to_vec());
+        }
+        if reader.pos != body.len() {
+            return Err(invalid("trailing data"));
+        }
+
+        let regex =
+            Regex::new(pattern).map_err(|e| TiktokenError::InvalidPattern(e.to_string()))?;
+        let special_regex = special_tokens_regex(&special_tokens_encoder)?;
+        Ok(CoreBPE {
+            encoder: Arc::new(encoder),
+            special_tokens_encoder,
+            decoder: Arc::new(decoder),
+            special_tokens_decoder,
+            regex_tls: vec![regex; MAX_NUM_THREADS],
+            special_regex_tls: vec![special_regex; MAX_NUM_THREADS],
+            sorted_token_bytes: Arc::new(sorted_token_bytes),
+        })
+    }
+
+    pub fn save_snapshot_file(&self, path: impl AsRef<Path>) -> Result<(), TiktokenError> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_snapshot())
+            .map_err(|e| TiktokenError::IoError(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn from_snapshot_file(path: impl AsRef<Path>) -> Result<Self, TiktokenError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .map_err(|e| TiktokenError::IoError(format!("{}: {}", path.display(), e)))?;
+        CoreBPE::from_snapshot(&data)
+    }
+}
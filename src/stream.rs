@@ -0,0 +1,327 @@
+use std::collections::{HashSet, LinkedList};
+use std::sync::Arc;
+
+use ocaml::{List, Pointer};
+
+use crate::handle::Encoding;
+use crate::{
+    byte_pair_encode, string_from_utf8, texts_from_list, tokens_from_ints, tokens_to_ints, unknown_token_as_given,
+    CoreBPE, DecodeErrors, Rank, TiktokenError,
+};
+
+impl CoreBPE {
+    // Encodes the part of `text` that later input cannot change and returns its tokens with the
+    // byte length of that part. The last two pieces are held back, since more text can also
+    // change the piece before the last one (with a contraction rule like `'re`, "we'r" splits
+    // into "we", "'", "r" but "we're" into "we", "'re"). So are the whitespace pieces before them
+    // (a regex like `\s+(?!\S)` splits whitespace differently depending on what follows) and a
+    // trailing prefix of an allowed special token, since the rest of the token may still arrive.
+    fn _encode_stable_prefix(
+        &self,
+        text: &str,
+        allowed_special: &HashSet<&str>,
+    ) -> Result<(Vec<Rank>, usize), TiktokenError> {
+        // (start, end, special token) of every piece. Only the pieces that are not held back are
+        // encoded, so a long last piece is not encoded again on every call.
+        let mut pieces = vec![];
+        self._for_each_piece_span(text, allowed_special, |start, end, special| {
+            pieces.push((start, end, special));
+            true
+        })?;
+        let is_whitespace = |&(start, end, _): &(usize, usize, Option<Rank>)| {
+            text[start..end].chars().all(char::is_whitespace)
+        };
+
+        let mut cut = pieces.len();
+        for _ in 0..2 {
+            if cut > 0 && pieces[cut - 1].2.is_none() {
+                cut -= 1;
+            }
+        }
+        let partial_special_start = allowed_special
+            .iter()
+            .filter_map(|special| {
+                (1..special.len())
+                    .rev()
+                    .find(|&len| text.as_bytes().ends_with(&special.as_bytes()[..len]))
+                    .map(|len| text.len() - len)
+            })
+            .min();
+        if let Some(partial_special_start) = partial_special_start {
+            while cut > 0 && pieces[cut - 1].1 > partial_special_start {
+                cut -= 1;
+            }
+        }
+        while cut > 0 && is_whitespace(&pieces[cut - 1]) {
+            cut -= 1;
+        }
+
+        let mut tokens = vec![];
+        for &(start, end, special) in &pieces[..cut] {
+            let piece = &text.as_bytes()[start..end];
+            match special.or_else(|| self.encoder.get(piece).copied()) {
+                Some(token) => tokens.push(token),
+                None => tokens.extend(byte_pair_encode(piece, &self.encoder)?),
+            }
+        }
+        let stable_len = match pieces.get(cut) {
+            Some(&(start, _, _)) => start,
+            None => pieces.last().map_or(0, |&(_, end, _)| end),
+        };
+        // The pattern need not match every character, so the partial special token can start
+        // before the first held back piece.
+        Ok((tokens, stable_len.min(partial_special_start.unwrap_or(stable_len))))
+    }
+}
+
+// Encodes text that arrives in chunks, such as streamed model output or socket reads. Chunks may
+// split UTF-8 sequences. `push` returns the tokens that no later chunk can change and `finish`
+// the rest, so the concatenated output equals `encode` of the whole text.
+#[ocaml::sig]
+pub struct StreamEncoder {
+    bpe: Arc<CoreBPE>,
+    allowed_special: HashSet<String>,
+    // Text whose tokens have not been returned yet.
+    text: String,
+    // Incomplete UTF-8 sequence at the end of the last chunk.
+    partial: Vec<u8>,
+    // Number of bytes received before `partial`, for error positions.
+    received: usize,
+    // Length of `text` after the last call that encoded it.
+    held_len: usize,
+}
+
+ocaml::custom!(StreamEncoder);
+
+impl StreamEncoder {
+    pub fn new(bpe: Arc<CoreBPE>, allowed_special: HashSet<String>) -> Self {
+        StreamEncoder {
+            bpe,
+            allowed_special,
+            text: String::new(),
+            partial: Vec::new(),
+            received: 0,
+            held_len: 0,
+        }
+    }
+
+    // Clears the state of the current stream.
+    fn reset(&mut self) {
+        self.text.clear();
+        self.partial.clear();
+        self.received = 0;
+        self.held_len = 0;
+    }
+
+    // Returns tokens that no later chunk can change. On error the encoder is left as it was.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<Rank>, TiktokenError> {
+        let mut bytes = self.partial.clone();
+        bytes.extend_from_slice(chunk);
+        let valid_len = match std::str::from_utf8(&bytes) {
+            Ok(_) => bytes.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(TiktokenError::InvalidUtf8(self.received + e.valid_up_to())),
+        };
+        let partial = bytes.split_off(valid_len);
+        // `bytes` was just validated.
+        let chunk = std::str::from_utf8(&bytes).unwrap();
+
+        // The text held back is scanned again only once it has at least doubled, so that a long
+        // piece arriving in small chunks costs linear time overall. Its tokens come out a few
+        // chunks later, but are the same.
+        let old_len = self.text.len();
+        self.text.push_str(chunk);
+        let mut tokens = vec![];
+        if self.text.len() >= 2 * self.held_len {
+            let allowed_special = self.allowed_special.iter().map(String::as_str).collect();
+            match self.bpe._encode_stable_prefix(&self.text, &allowed_special) {
+                Ok((stable_tokens, stable_len)) => {
+                    tokens = stable_tokens;
+                    self.text.drain(..stable_len);
+                    self.held_len = self.text.len();
+                }
+                Err(e) => {
+                    self.text.truncate(old_len);
+                    return Err(e);
+                }
+            }
+        }
+        self.partial = partial;
+        self.received += valid_len;
+        Ok(tokens)
+    }
+
+    // Encodes the remaining text and resets the encoder for a new stream, also when it fails
+    // because the input ended in the middle of a UTF-8 sequence.
+    pub fn finish(&mut self) -> Result<Vec<Rank>, TiktokenError> {
+        let result = if self.partial.is_empty() {
+            let allowed_special = self.allowed_special.iter().map(String::as_str).collect();
+            self.bpe._encode_native(&self.text, &allowed_special).map(|(tokens, _)| tokens)
+        } else {
+            Err(TiktokenError::InvalidUtf8(self.received))
+        };
+        self.reset();
+        result
+    }
+}
+
+// Function to start encoding a stream of text chunks, encoding the listed special tokens as such
+#[ocaml::func]
+#[ocaml::sig("encoding -> string list -> (stream_encoder, tiktoken_error) result")]
+pub fn encoding_stream_encoder(
+    encoding: &Encoding,
+    allowed_special: List<&[u8]>,
+) -> Result<Pointer<StreamEncoder>, TiktokenError> {
+    let allowed_special = texts_from_list(allowed_special)?.into_iter().collect();
+    Ok(StreamEncoder::new(Arc::clone(&encoding.bpe), allowed_special).into())
+}
+
+// Function to add a chunk of text to a stream, returning the tokens that are now final
+#[ocaml::func]
+#[ocaml::sig("stream_encoder -> string -> (int array, tiktoken_error) result")]
+pub fn stream_encoder_push(encoder: &mut StreamEncoder, chunk: &[u8]) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    encoder.push(chunk).map(tokens_to_ints)
+}
+
+// Function to end a stream, returning its remaining tokens. The encoder can then start a new one.
+#[ocaml::func]
+#[ocaml::sig("stream_encoder -> (int array, tiktoken_error) result")]
+pub fn stream_encoder_finish(encoder: &mut StreamEncoder) -> Result<Vec<ocaml::Int>, TiktokenError> {
+    encoder.finish().map(tokens_to_ints)
+}
+
+// What a stream decoder produces: text, or a special token, reported apart from the text so that
+// a literal "<|endoftext|>" in the text cannot be mistaken for one.
+#[derive(Debug, PartialEq, Eq, ocaml::ToValue)]
+#[ocaml::sig("Text of string | Special_token of string")]
+pub enum DecodeEvent {
+    Text(String),
+    SpecialToken(String),
+}
+
+// Decodes tokens that arrive one at a time, such as sampled model output. A token may end in the
+// middle of a UTF-8 sequence; those bytes are held back until the tokens completing the sequence
+// arrive, so every `Text` event is whole characters.
+#[derive(Clone)]
+#[ocaml::sig]
+pub struct StreamDecoder {
+    bpe: Arc<CoreBPE>,
+    errors: DecodeErrors,
+    // Decoded bytes not returned yet: at most an incomplete UTF-8 sequence between calls.
+    pending: Vec<u8>,
+    // Number of bytes returned before `pending`, for error positions.
+    decoded: usize,
+}
+
+ocaml::custom!(StreamDecoder);
+
+// Length of the incomplete UTF-8 sequence at the end of `bytes`, 0 if there is none.
+fn incomplete_suffix_len(bytes: &[u8]) -> usize {
+    // A sequence is at most four bytes, so at most three can be missing their continuation.
+    (1..=bytes.len().min(3))
+        .find(|&len| {
+            let suffix = &bytes[bytes.len() - len..];
+            matches!(
+                std::str::from_utf8(suffix),
+                Err(e) if e.valid_up_to() == 0 && e.error_len().is_none()
+            )
+        })
+        .unwrap_or(0)
+}
+
+impl StreamDecoder {
+    pub fn new(bpe: Arc<CoreBPE>, errors: DecodeErrors) -> Self {
+        StreamDecoder {
+            bpe,
+            errors,
+            pending: Vec::new(),
+            decoded: 0,
+        }
+    }
+
+    // Adds the pending bytes to `events` as text, except for an incomplete UTF-8 sequence at the
+    // end unless `complete` is set. Invalid bytes are handled according to `self.errors`.
+    fn flush_text(&mut self, events: &mut Vec<DecodeEvent>, complete: bool) -> Result<(), TiktokenError> {
+        let held = if complete { 0 } else { incomplete_suffix_len(&self.pending) };
+        let held = self.pending.split_off(self.pending.len() - held);
+        let bytes = std::mem::replace(&mut self.pending, held);
+        let len = bytes.len();
+        let text = string_from_utf8(bytes, self.errors).map_err(|e| match e {
+            TiktokenError::InvalidUtf8(pos) => TiktokenError::InvalidUtf8(self.decoded + pos),
+            e => e,
+        })?;
+        self.decoded += len;
+        if !text.is_empty() {
+            events.push(DecodeEvent::Text(text));
+        }
+        Ok(())
+    }
+
+    // Returns the events completed by `tokens`. On error the decoder is left as it was, so text
+    // held back from earlier calls is not lost with the failed tokens.
+    pub fn push(&mut self, tokens: &[Rank]) -> Result<Vec<DecodeEvent>, TiktokenError> {
+        let mut next = self.clone();
+        let events = next.decode_tokens(tokens)?;
+        *self = next;
+        Ok(events)
+    }
+
+    fn decode_tokens(&mut self, tokens: &[Rank]) -> Result<Vec<DecodeEvent>, TiktokenError> {
+        let bpe = Arc::clone(&self.bpe);
+        let mut events = vec![];
+        for (i, token) in tokens.iter().enumerate() {
+            if let Some(bytes) = bpe.decoder.get(token) {
+                self.pending.extend_from_slice(bytes);
+                continue;
+            }
+            let special = bpe
+                .special_tokens_decoder
+                .get(token)
+                .ok_or(TiktokenError::UnknownTokenAt(*token as ocaml::Int, i))?;
+            // A special token ends the text before it, even in the middle of a UTF-8 sequence.
+            self.flush_text(&mut events, true)?;
+            self.decoded += special.len();
+            // Special tokens are created from strings.
+            events.push(DecodeEvent::SpecialToken(String::from_utf8_lossy(special).into_owned()));
+        }
+        self.flush_text(&mut events, false)?;
+        Ok(events)
+    }
+
+    // Returns the remaining text and resets the decoder for a new stream. Bytes of an incomplete
+    // UTF-8 sequence at the end are handled according to `self.errors`.
+    pub fn finish(&mut self) -> Result<Vec<DecodeEvent>, TiktokenError> {
+        let mut events = vec![];
+        let result = self.flush_text(&mut events, true);
+        self.decoded = 0;
+        result.map(|()| events)
+    }
+}
+
+// Function to start decoding a stream of tokens, handling invalid UTF-8 as selected by `errors`
+#[ocaml::func]
+#[ocaml::sig("encoding -> decode_errors -> stream_decoder")]
+pub fn encoding_stream_decoder(encoding: &Encoding, errors: DecodeErrors) -> Pointer<StreamDecoder> {
+    StreamDecoder::new(Arc::clone(&encoding.bpe), errors).into()
+}
+
+// Function to add tokens to a stream, returning the complete text and special tokens they add
+#[ocaml::func]
+#[ocaml::sig("stream_decoder -> int array -> (decode_event list, tiktoken_error) result")]
+pub fn stream_decoder_push(
+    decoder: &mut StreamDecoder,
+    tokens: Vec<ocaml::Int>,
+) -> Result<LinkedList<DecodeEvent>, TiktokenError> {
+    let events = decoder
+        .push(&tokens_from_ints(&tokens))
+        .map_err(|e| unknown_token_as_given(&tokens, e))?;
+    Ok(events.into_iter().collect())
+}
+
+// Function to end a stream, returning the text still held back. The decoder can then start a new
+// one.
+#[ocaml::func]
+#[ocaml::sig("stream_decoder -> (decode_event list, tiktoken_error) result")]
+pub fn stream_decoder_finish(decoder: &mut StreamDecoder) -> Result<LinkedList<DecodeEvent>, TiktokenError> {
+    Ok(decoder.finish()?.into_iter().collect())
+}
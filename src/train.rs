@@ -0,0 +1,166 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, LinkedList};
+
+use fancy_regex::Regex;
+use ocaml::{List, ToValue, Value};
+
+use crate::{
+    encoder_from_value, load, match_error, text_from_bytes, texts_from_list, without_runtime_lock, Rank,
+    TiktokenError,
+};
+
+type Pair = (Rank, Rank);
+
+// Learns a byte-level BPE vocabulary of `vocab_size` tokens from `texts`, the way tiktoken's
+// reference trainer does: starting from the 256 single bytes, the most frequent pair of adjacent
+// tokens within the regex pieces of the corpus becomes a new token, until the vocabulary is full
+// or no piece has two tokens left. Ranks follow merge order, which is what `_byte_pair_merge`
+// relies on to reproduce the training merges.
+pub fn train_ranks(
+    texts: &[String],
+    pattern: &str,
+    vocab_size: usize,
+) -> Result<HashMap<Vec<u8>, Rank>, TiktokenError> {
+    if vocab_size < 256 {
+        return Err(TiktokenError::BadArgument(format!(
+            "vocabulary size {} must be at least 256",
+            vocab_size
+        )));
+    }
+    let regex = Regex::new(pattern).map_err(|e| TiktokenError::InvalidPattern(e.to_string()))?;
+
+    // Every distinct piece is merged once, weighted by how often it occurs.
+    let mut piece_counts: HashMap<&[u8], usize> = HashMap::new();
+    for text in texts {
+        for mat in regex.find_iter(text) {
+            *piece_counts.entry(mat.map_err(match_error)?.as_str().as_bytes()).or_default() += 1;
+        }
+    }
+    let mut words: Vec<(Vec<Rank>, usize)> = piece_counts
+        .into_iter()
+        .map(|(piece, count)| (piece.iter().map(|&b| b as Rank).collect(), count))
+        .collect();
+
+    let mut token_bytes: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+    let mut ranks: HashMap<Vec<u8>, Rank> = token_bytes
+        .iter()
+        .enumerate()
+        .map(|(rank, bytes)| (bytes.clone(), rank as Rank))
+        .collect();
+
+    // Occurrences of each pair, and the words it may occur in.
+    let mut pair_counts: HashMap<Pair, usize> = HashMap::new();
+    let mut pair_words: HashMap<Pair, HashSet<usize>> = HashMap::new();
+    for (i, (tokens, count)) in words.iter().enumerate() {
+        for pair in tokens.windows(2) {
+            *pair_counts.entry((pair[0], pair[1])).or_default() += count;
+            pair_words.entry((pair[0], pair[1])).or_default().insert(i);
+        }
+    }
+
+    // Pairs by count, ties going to the lexicographically smallest bytes so the result does not
+    // depend on hash order. An entry is pushed whenever the count of its pair changes, and entries
+    // whose count is out of date are skipped when popped.
+    let entry = |pair: Pair, count: usize, token_bytes: &[Vec<u8>]| {
+        let bytes = (token_bytes[pair.0 as usize].clone(), token_bytes[pair.1 as usize].clone());
+        (count, Reverse(bytes), pair)
+    };
+    let mut queue: BinaryHeap<_> = pair_counts
+        .iter()
+        .map(|(&pair, &count)| entry(pair, count, &token_bytes))
+        .collect();
+    // Pairs that spell the bytes of an existing token, see below.
+    let mut skipped: HashSet<Pair> = HashSet::new();
+
+    while ranks.len() < vocab_size {
+        let Some((count, _, pair)) = queue.pop() else {
+            break;
+        };
+        if skipped.contains(&pair) || pair_counts.get(&pair) != Some(&count) {
+            continue;
+        }
+
+        let merged = [
+            token_bytes[pair.0 as usize].as_slice(),
+            token_bytes[pair.1 as usize].as_slice(),
+        ]
+        .concat();
+        // A second pair spelling the bytes of an existing token is not merged: the token keeps its
+        // earlier rank, so `_byte_pair_merge` would apply this merge at a different point than
+        // training did.
+        if ranks.contains_key(&merged) {
+            skipped.insert(pair);
+            continue;
+        }
+        let token = token_bytes.len() as Rank;
+        ranks.insert(merged.clone(), token);
+        token_bytes.push(merged);
+
+        let mut changed = HashSet::new();
+        for i in pair_words.remove(&pair).unwrap_or_default() {
+            let (tokens, count) = &mut words[i];
+            for old in tokens.windows(2) {
+                let old = (old[0], old[1]);
+                if let Some(old_count) = pair_counts.get_mut(&old) {
+                    *old_count -= *count;
+                    if *old_count == 0 {
+                        pair_counts.remove(&old);
+                    }
+                    changed.insert(old);
+                }
+            }
+            let mut merged_tokens = Vec::with_capacity(tokens.len());
+            let mut j = 0;
+            while j < tokens.len() {
+                if j + 1 < tokens.len() && (tokens[j], tokens[j + 1]) == pair {
+                    merged_tokens.push(token);
+                    j += 2;
+                } else {
+                    merged_tokens.push(tokens[j]);
+                    j += 1;
+                }
+            }
+            *tokens = merged_tokens;
+            for new in tokens.windows(2) {
+                *pair_counts.entry((new[0], new[1])).or_default() += *count;
+                pair_words.entry((new[0], new[1])).or_default().insert(i);
+                changed.insert((new[0], new[1]));
+            }
+        }
+        pair_counts.remove(&pair);
+        for pair in changed {
+            if let Some(&count) = pair_counts.get(&pair) {
+                queue.push(entry(pair, count, &token_bytes));
+            }
+        }
+    }
+    Ok(ranks)
+}
+
+// Function to learn a vocabulary of `vocab_size` tokens from a corpus, split into pieces by
+// `pattern`. Returns the ranks in the form encoding_new takes, in rank order. The runtime lock is
+// released while training.
+#[ocaml::func]
+#[ocaml::sig("string list -> string -> int -> ((bytes * int) list, tiktoken_error) result")]
+pub fn train_bpe(
+    texts: List<&[u8]>,
+    pattern: &[u8],
+    vocab_size: usize,
+) -> Result<LinkedList<(Value, ocaml::Int)>, TiktokenError> {
+    let texts = texts_from_list(texts)?;
+    let pattern = text_from_bytes(pattern)?.to_string();
+    let ranks = without_runtime_lock(gc, || train_ranks(&texts, &pattern, vocab_size))?;
+    let mut ranks: Vec<(Vec<u8>, Rank)> = ranks.into_iter().collect();
+    ranks.sort_unstable_by_key(|&(_, rank)| rank);
+    Ok(ranks
+        .into_iter()
+        .map(|(bytes, rank)| (bytes.as_slice().to_value(gc), rank as ocaml::Int))
+        .collect())
+}
+
+// Function to save ranks, such as those returned by train_bpe, as a .tiktoken rank file
+#[ocaml::func]
+#[ocaml::sig("(bytes * int) list -> string -> (unit, tiktoken_error) result")]
+pub fn write_tiktoken_file(encoder: Value, path: &[u8]) -> Result<(), TiktokenError> {
+    load::write_tiktoken_bpe_file(text_from_bytes(path)?, &encoder_from_value(encoder)?)
+}
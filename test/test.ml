@@ -1,5 +1,15 @@
 (* File: test_ocaml_rust_tiktok.ml *)
 
+(* Rank file with the tokens "a", "b", "ab" and " ", split into pieces by "\\w+| ", plus
+   <|endoftext|>; shared by the tests that need a small encoding *)
+let small_contents = "YQ== 0\nYg== 1\nYWI= 2\nIA== 3\n"
+let small_special_tokens = [("<|endoftext|>", 4)]
+let small_pattern = "\\w+| "
+
+let small_encoding () =
+  Result.get_ok
+    (Ocaml_rust_tiktok.encoding_new_from_string small_contents small_special_tokens small_pattern)
+
 (* The Rust function should be available via FFI and accessible from the Ocaml_rust_tiktok module *)
 let test_core_bpe_new () =
   (* Create a sample encoder list, special tokens encoder list, and a pattern *)
@@ -8,11 +18,558 @@ let test_core_bpe_new () =
   let pattern = "pattern" in
 
   (* Call the Rust function from the Ocaml_rust_tiktok module *)
-  let id = Ocaml_rust_tiktok.core_bpe_new encoder special_tokens_encoder pattern in
+  let id = Result.get_ok (Ocaml_rust_tiktok.core_bpe_new encoder special_tokens_encoder pattern) in
 
   (* Print the returned id to verify the result *)
   Printf.printf "Returned core BPE id: %d\n" id
 
-(* Run the test *)
-let () = test_core_bpe_new ()
+let test_core_bpe_new_from_string () =
+  (* Rank file contents: base64 token, space, rank per line ("a", "b", "ab") *)
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let special_tokens_encoder = [("<|endoftext|>", 3)] in
+  let pattern = "\\w+" in
+
+  let id =
+    Result.get_ok (Ocaml_rust_tiktok.core_bpe_new_from_string contents special_tokens_encoder pattern)
+  in
+
+  Printf.printf "Returned core BPE id from rank file contents: %d\n" id
+
+let test_list_encoding_names () =
+  let names = Ocaml_rust_tiktok.list_encoding_names () in
+  assert (Array.mem "cl100k_base" names);
+  Printf.printf "Built-in encodings: %s\n" (String.concat ", " (Array.to_list names))
+
+let test_encoding_name_for_model () =
+  assert (Ocaml_rust_tiktok.encoding_name_for_model "gpt-4o" = Some "o200k_base");
+  assert (Ocaml_rust_tiktok.encoding_name_for_model "gpt-4-0613" = Some "cl100k_base");
+  assert (Ocaml_rust_tiktok.encoding_name_for_model "ft:gpt-4o-mini:org::id" = Some "o200k_base");
+  assert (Ocaml_rust_tiktok.encoding_name_for_model "code-davinci-002" = Some "p50k_base");
+  assert (Ocaml_rust_tiktok.encoding_name_for_model "not-a-model" = None);
+  Printf.printf "Model to encoding lookups passed\n"
+
+let test_core_bpe_encode_decode () =
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let id =
+    Result.get_ok (Ocaml_rust_tiktok.core_bpe_new_from_string contents [("<|endoftext|>", 3)] "\\w+| ")
+  in
+
+  assert (Ocaml_rust_tiktok.core_bpe_encode_ordinary id "aab" = Ok [|0; 2|]);
+  assert (Ocaml_rust_tiktok.core_bpe_encode id "ab<|endoftext|>" ["<|endoftext|>"] = Ok [|2; 3|]);
+  assert (Ocaml_rust_tiktok.core_bpe_encode_bytes id (Bytes.of_string "ba") = Ok [|1; 0|]);
+  assert (Ocaml_rust_tiktok.core_bpe_encode_single_token id (Bytes.of_string "ab") = Ok 2);
+  assert (Ocaml_rust_tiktok.core_bpe_encode_single_token id (Bytes.of_string "<|endoftext|>") = Ok 3);
+  assert (Result.is_error (Ocaml_rust_tiktok.core_bpe_encode_single_token id (Bytes.of_string "c")));
+  assert (Ocaml_rust_tiktok.core_bpe_encode_single_piece id (Bytes.of_string "aab") = Ok [|0; 2|]);
+  assert (Ocaml_rust_tiktok.core_bpe_decode_bytes id [|0; 2; 3|] = Ok (Bytes.of_string "aab<|endoftext|>"));
+  assert (Ocaml_rust_tiktok.core_bpe_decode_single_token_bytes id 1 = Ok (Bytes.of_string "b"));
+  assert (Array.length (Result.get_ok (Ocaml_rust_tiktok.core_bpe_token_byte_values id)) = 3);
+  Printf.printf "Encode/decode bindings passed\n"
+
+let test_encoding_handle () =
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let enc =
+    Result.get_ok (Ocaml_rust_tiktok.encoding_new_from_string contents [("<|endoftext|>", 3)] "\\w+| ")
+  in
+
+  assert (Ocaml_rust_tiktok.encoding_encode_ordinary enc "aab" = Ok [|0; 2|]);
+  assert (Ocaml_rust_tiktok.encoding_encode enc "ab<|endoftext|>" ["<|endoftext|>"] = Ok [|2; 3|]);
+  assert (Ocaml_rust_tiktok.encoding_decode_bytes enc [|0; 2; 3|] = Ok (Bytes.of_string "aab<|endoftext|>"));
+  assert (Ocaml_rust_tiktok.encoding_encode_single_token enc (Bytes.of_string "ab") = Ok 2);
+
+  (* Handles are garbage collected; the finalizer frees the Rust side *)
+  Gc.full_major ();
+  let live = Ocaml_rust_tiktok.encoding_live_handles () in
+  let[@inline never] use_and_drop () =
+    let enc = Ocaml_rust_tiktok.encoding_new_from_string contents [] "\\w+" in
+    assert (Ocaml_rust_tiktok.encoding_live_handles () = live + 1);
+    ignore (Sys.opaque_identity enc)
+  in
+  use_and_drop ();
+  Gc.full_major ();
+  assert (Ocaml_rust_tiktok.encoding_live_handles () = live);
+  Printf.printf "Typed encoding handle passed\n"
+
+let test_core_bpe_lifecycle () =
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let id = Result.get_ok (Ocaml_rust_tiktok.core_bpe_new_from_string contents [] "\\w+") in
+
+  assert (Ocaml_rust_tiktok.core_bpe_exists id);
+  assert (List.mem_assoc id (Ocaml_rust_tiktok.core_bpe_list_ids ()));
+  assert (List.assoc id (Ocaml_rust_tiktok.core_bpe_list_ids ()) > 0);
+  assert (Ocaml_rust_tiktok.core_bpe_free id);
+  assert (not (Ocaml_rust_tiktok.core_bpe_exists id));
+  assert (not (List.mem_assoc id (Ocaml_rust_tiktok.core_bpe_list_ids ())));
+  (* Freeing twice is harmless and IDs are never reused *)
+  assert (not (Ocaml_rust_tiktok.core_bpe_free id));
+  assert (Ocaml_rust_tiktok.core_bpe_encode_ordinary id "ab" = Error (Ocaml_rust_tiktok.Unknown_handle id));
+  assert (Ocaml_rust_tiktok.core_bpe_new_from_string contents [] "\\w+" <> Ok id);
+  Printf.printf "CoreBPE lifecycle passed\n"
+
+let test_errors () =
+  let open Ocaml_rust_tiktok in
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  (match core_bpe_new_from_string contents [] "(" with
+   | Error (Invalid_pattern _) -> ()
+   | _ -> assert false);
+  assert (core_bpe_new_from_string "YQ== 0\nYg== 0\n" [] "\\w+" = Error (Duplicate_rank 0));
+  List.iter
+    (fun rank ->
+      (match encoding_new [(Bytes.of_string "a", rank)] [] "\\w+" with
+       | Error (Bad_argument _) -> ()
+       | _ -> assert false);
+      match encoding_new [] [("<|endoftext|>", rank)] "\\w+" with
+      | Error (Bad_argument _) -> ()
+      | _ -> assert false)
+    [-1; 1 lsl 32; (1 lsl 32) - 1];
+  (match core_bpe_new_from_string "not base64 0\n" [] "\\w+" with
+   | Error (Invalid_rank_file _) -> ()
+   | _ -> assert false);
+  (match encoding_new_from_file "/nonexistent.tiktoken" [] "\\w+" with
+   | Error (Io_error _) -> ()
+   | _ -> assert false);
+  assert (get_encoding_from_file "not_an_encoding" "/nonexistent.tiktoken"
+          = Error (Unknown_encoding "not_an_encoding"));
+  assert (encoding_for_model "not-a-model" = Error (Unknown_model "not-a-model"));
+
+  let enc = Result.get_ok (encoding_new_from_string contents [] "\\w+") in
+  assert (encoding_encode_ordinary enc "ab\xff" = Error (Invalid_utf8 2));
+  assert (encoding_decode_single_token_bytes enc 42 = Error (Unknown_token 42));
+  assert (encoding_encode_single_token enc (Bytes.of_string "c") = Error (Unknown_piece "c"));
+  (* The vocabulary has no token for "c", so text containing it cannot be encoded. *)
+  assert (encoding_encode_ordinary enc "abc" = Error (Unknown_piece "c"));
+  assert (encoding_count_tokens_ordinary enc "abc" = Error (Unknown_piece "c"));
+  assert (encoding_encode_bytes enc (Bytes.of_string "c") = Error (Unknown_piece "c"));
+  assert (encoding_encode_single_piece enc (Bytes.of_string "ac") = Error (Unknown_piece "c"));
+  assert (encoding_encode_single_piece enc Bytes.empty = Ok [||]);
+  assert (string_of_error (Unknown_token_at (42, 1)) = "Token 42 at position 1 not found");
+  assert (string_of_error (Io_error "no such file") = "I/O error: no such file");
+  (* A pattern that compiles but exceeds the backtracking limit while matching *)
+  let enc = Result.get_ok (encoding_new_from_string contents [] "(a|a)+(?=b)|\\S") in
+  (match encoding_encode_ordinary enc (String.make 40 'a') with
+   | Error (Invalid_pattern _) -> ()
+   | _ -> assert false);
+  Printf.printf "Error reporting passed\n"
+
+let test_decode_unknown_tokens () =
+  let open Ocaml_rust_tiktok in
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [("<|endoftext|>", 3)] "\\w+") in
+  let id = Result.get_ok (core_bpe_new_from_string contents [("<|endoftext|>", 3)] "\\w+") in
+
+  (* Strict decoding names the offending token and its position *)
+  assert (encoding_decode_bytes enc [|0; 99; 1|] = Error (Unknown_token_at (99, 1)));
+  assert (core_bpe_decode_bytes id [|2; 3; 7|] = Error (Unknown_token_at (7, 2)));
+
+  (* Lenient decoding substitutes the replacement bytes *)
+  let replacement = Bytes.of_string "\xef\xbf\xbd" in
+  assert (encoding_decode_bytes_lossy enc [|0; 99; 1|] replacement = Bytes.of_string "a\xef\xbf\xbdb");
+  assert (encoding_decode_bytes_lossy enc [|99; 3|] Bytes.empty = Bytes.of_string "<|endoftext|>");
+
+  (* Ints that cannot be ranks are unknown tokens too, reported as given *)
+  let big = 1 lsl 40 in
+  assert (encoding_decode_bytes enc [|0; big|] = Error (Unknown_token_at (big, 1)));
+  assert (encoding_decode_bytes enc [|-1; 99|] = Error (Unknown_token_at (-1, 0)));
+  assert (encoding_decode_bytes enc [|99; -1|] = Error (Unknown_token_at (99, 0)));
+  assert (encoding_decode enc [|0; (1 lsl 32) + 1|] Strict = Error (Unknown_token_at ((1 lsl 32) + 1, 1)));
+  assert (core_bpe_decode_bytes id [|2; -7|] = Error (Unknown_token_at (-7, 1)));
+  assert (encoding_decode_batch enc [[|0|]; [|1; big|]] 2 = Error (Unknown_token_at (big, 1)));
+  assert (encoding_decode_single_token_bytes enc (-1) = Error (Unknown_token (-1)));
+  assert (core_bpe_decode_single_token_bytes id big = Error (Unknown_token big));
+  assert (encoding_decode_bytes_lossy enc [|0; -1; big; 1|] replacement
+          = Bytes.of_string "a\xef\xbf\xbd\xef\xbf\xbdb");
+  Printf.printf "Unknown token decoding passed\n"
+
+let test_disallowed_special () =
+  let open Ocaml_rust_tiktok in
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let specials = [("<|endoftext|>", 3); ("<|fim_prefix|>", 4)] in
+  let enc = Result.get_ok (encoding_new_from_string contents specials "\\w+") in
+
+  (* tiktoken's default: nothing allowed, everything disallowed *)
+  assert (encoding_encode_with_special enc "ab" (Tokens []) All = Ok [|2|]);
+  assert (encoding_encode_with_special enc "ab<|endoftext|>" (Tokens []) All
+          = Error (Disallowed_special_token "<|endoftext|>"));
+  assert (encoding_encode_with_special enc "ab<|fim_prefix|>" All All = Ok [|2; 4|]);
+  assert (encoding_encode_with_special enc "ab<|endoftext|>" (Tokens ["<|endoftext|>"]) All = Ok [|2; 3|]);
+  assert (encoding_encode_with_special enc "<|fim_prefix|>" (Tokens ["<|endoftext|>"]) All
+          = Error (Disallowed_special_token "<|fim_prefix|>"));
+  Printf.printf "Disallowed special tokens passed\n"
+
+let test_long_piece () =
+  let open Ocaml_rust_tiktok in
+  (* "a", "b", "ab", "abab": a long run of "ab" is a single regex piece merged with the heap *)
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\nYWJhYg== 3\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [] "\\w+") in
+  let repeat s n = String.concat "" (List.init n (fun _ -> s)) in
+
+  assert (encoding_encode_ordinary enc (repeat "ab" 5000) = Ok (Array.make 2500 3));
+  assert (encoding_encode_ordinary enc (repeat "ab" 5001) = Ok (Array.append (Array.make 2500 3) [|2|]));
+  assert (encoding_encode_ordinary enc ("b" ^ repeat "ab" 1000) = Ok (Array.append [|1|] (Array.make 500 3)));
+  (* Short pieces take the original path and agree with it *)
+  assert (encoding_encode_ordinary enc (repeat "ab" 5) = Ok [|3; 3; 2|]);
+  Printf.printf "Long piece merging passed\n"
+
+let test_batch () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  let docs = List.init 100 (fun i -> String.concat " " (List.init (i mod 7 + 1) (fun _ -> "ab"))) in
+  let expected = List.map (fun doc -> Result.get_ok (encoding_encode_ordinary enc doc)) docs in
+
+  (* Results come back in input order whatever the thread count *)
+  List.iter (fun num_threads ->
+      assert (encoding_encode_ordinary_batch enc docs num_threads = Ok expected);
+      assert (encoding_decode_batch enc expected num_threads = Ok (List.map Bytes.of_string docs)))
+    [0; 1; 4; 200];
+  assert (encoding_encode_ordinary_batch enc [] 4 = Ok []);
+  assert (encoding_encode_batch enc ["ab<|endoftext|>"; "b"] ["<|endoftext|>"] 2 = Ok [[|2; 4|]; [|1|]]);
+  assert (encoding_decode_batch enc [[|0|]; [|1; 42|]; [|7|]] 2 = Error (Unknown_token_at (42, 1)));
+  assert (encoding_encode_ordinary_batch enc ["ab"; "\xff"] 2 = Error (Invalid_utf8 0));
+  Printf.printf "Batch encoding passed\n"
+
+let test_release_runtime_lock () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  let id = Result.get_ok (core_bpe_new_from_string small_contents small_special_tokens small_pattern) in
+  (* Large enough to be encoded and decoded without the runtime lock *)
+  let text = String.concat " " (List.init 20_000 (fun i -> if i mod 3 = 0 then "ab" else "ba")) in
+  let tokens = Result.get_ok (encoding_encode_ordinary enc text) in
+  assert (Array.length tokens = 6_667 + (2 * 13_333) + 19_999);
+  assert (encoding_decode_bytes enc tokens = Ok (Bytes.of_string text));
+  assert (core_bpe_encode_ordinary id text = Ok tokens);
+  assert (core_bpe_decode_bytes id tokens = Ok (Bytes.of_string text));
+  assert (encoding_encode_bytes enc (Bytes.of_string text) = Ok tokens);
+  assert (encoding_encode enc (text ^ "<|endoftext|>") ["<|endoftext|>"] = Ok (Array.append tokens [|4|]));
+  assert (encoding_encode_with_special enc (text ^ "<|endoftext|>") (Tokens []) All
+          = Error (Disallowed_special_token "<|endoftext|>"));
+  assert (encoding_decode_bytes enc (Array.append tokens [|42|])
+          = Error (Unknown_token_at (42, Array.length tokens)));
+
+  (* Several OCaml threads encoding at once while the GC runs *)
+  let results = Array.make 4 [||] in
+  let threads = List.init 4 (fun i ->
+      Thread.create (fun () ->
+          results.(i) <- Result.get_ok (encoding_encode_ordinary enc text);
+          Gc.compact ()) ()) in
+  List.iter Thread.join threads;
+  Array.iter (fun r -> assert (r = tokens)) results;
+  Printf.printf "Releasing the runtime lock passed\n"
+
+let test_bigarray () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  let to_array ba = Array.init (Bigarray.Array1.dim ba) (fun i -> Int32.to_int ba.{i}) in
+
+  let ba = Result.get_ok (encoding_encode_ordinary_bigarray enc "ab ba") in
+  assert (Bigarray.Array1.dim ba = 4);
+  assert (to_array ba = [|2; 3; 1; 0|]);
+  assert (encoding_decode_bytes_bigarray enc ba = Ok (Bytes.of_string "ab ba"));
+  let ba = Result.get_ok (encoding_encode_bigarray enc "ab<|endoftext|>" ["<|endoftext|>"]) in
+  assert (to_array ba = [|2; 4|]);
+  assert (Bigarray.Array1.dim (Result.get_ok (encoding_encode_ordinary_bigarray enc "")) = 0);
+  assert (encoding_decode_bytes_bigarray enc (Bigarray.Array1.create Bigarray.int32 Bigarray.c_layout 0)
+          = Ok Bytes.empty);
+
+  (* Out of range and negative int32 values are unknown tokens *)
+  let bad = Bigarray.Array1.of_array Bigarray.int32 Bigarray.c_layout [|0l; 42l; -1l|] in
+  assert (encoding_decode_bytes_bigarray enc bad = Error (Unknown_token_at (42, 1)));
+  (* A negative value is reported as given, not as its unsigned bit pattern *)
+  let bad = Bigarray.Array1.of_array Bigarray.int32 Bigarray.c_layout [|0l; -1l; 42l|] in
+  assert (encoding_decode_bytes_bigarray enc bad = Error (Unknown_token_at (-1, 1)));
+  let bad = Bigarray.Array1.of_array Bigarray.int32 Bigarray.c_layout [|Int32.min_int|] in
+  assert (encoding_decode_bytes_bigarray enc bad = Error (Unknown_token_at (Int32.to_int Int32.min_int, 0)));
+
+  (* Agrees with the int array functions on a large document *)
+  let text = String.concat " " (List.init 20_000 (fun i -> if i mod 3 = 0 then "ab" else "ba")) in
+  let ba = Result.get_ok (encoding_encode_ordinary_bigarray enc text) in
+  assert (Ok (to_array ba) = encoding_encode_ordinary enc text);
+  assert (encoding_decode_bytes_bigarray enc ba = Ok (Bytes.of_string text));
+  Printf.printf "Bigarray token arrays passed\n"
+
+let test_count_tokens () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  let count_of tokens = Array.length (Result.get_ok tokens) in
+  let texts = [""; "ab"; "ab ba"; String.make 2000 'a' ^ "ba"] in
+
+  List.iter (fun text ->
+      assert (encoding_count_tokens_ordinary enc text = Ok (count_of (encoding_encode_ordinary enc text))))
+    texts;
+  let texts = "ab<|endoftext|>ba" :: "<|endoftext|><|endoftext|>" :: texts in
+  List.iter (fun text ->
+      assert (encoding_count_tokens enc text ["<|endoftext|>"]
+              = Ok (count_of (encoding_encode enc text ["<|endoftext|>"]))))
+    texts;
+  assert (encoding_count_tokens enc "ab<|endoftext|>ba" ["<|endoftext|>"] = Ok 4);
+  assert (encoding_count_tokens_batch enc texts ["<|endoftext|>"] 2
+          = Ok (Array.of_list (List.map (fun text -> count_of (encoding_encode enc text ["<|endoftext|>"])) texts)));
+  assert (encoding_count_tokens_ordinary enc "\xff" = Error (Invalid_utf8 0));
+  Printf.printf "Token counting passed\n"
+
+let test_truncate () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  (* Pieces: "ab" [2], " " [3], "ba" [1; 0], " " [3], "ab" [2] *)
+  let text = "ab ba ab" in
+
+  assert (encoding_encode_truncated enc text [] 6 = Ok ([|2; 3; 1; 0; 3; 2|], 8));
+  assert (encoding_encode_truncated enc text [] 4 = Ok ([|2; 3; 1; 0|], 5));
+  (* "ba" does not fit whole, so it is dropped rather than split *)
+  assert (encoding_encode_truncated enc text [] 3 = Ok ([|2; 3|], 3));
+  assert (encoding_encode_truncated enc text [] 0 = Ok ([||], 0));
+  assert (encoding_encode_truncated enc "" [] 5 = Ok ([||], 0));
+
+  assert (encoding_encode_truncated_tail enc text [] 6 = Ok ([|2; 3; 1; 0; 3; 2|], 0));
+  assert (encoding_encode_truncated_tail enc text [] 4 = Ok ([|1; 0; 3; 2|], 3));
+  assert (encoding_encode_truncated_tail enc text [] 3 = Ok ([|3; 2|], 5));
+  assert (encoding_encode_truncated_tail enc text [] 0 = Ok ([||], 8));
+
+  (* Allowed special tokens are single pieces *)
+  let text = "ab<|endoftext|>ba" in
+  assert (encoding_encode_truncated enc text ["<|endoftext|>"] 2 = Ok ([|2; 4|], 15));
+  assert (encoding_encode_truncated_tail enc text ["<|endoftext|>"] 2 = Ok ([|1; 0|], 15));
+  assert (encoding_encode_truncated_tail enc text ["<|endoftext|>"] 3 = Ok ([|4; 1; 0|], 2));
+  Printf.printf "Truncation passed\n"
+
+let test_chunk () =
+  let open Ocaml_rust_tiktok in
+  (* "a", "b", "ab", " ", ".", "\n" *)
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\nIA== 3\nLg== 4\nCg== 5\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [("<|endoftext|>", 6)] "\\w+|[^\\w]") in
+  let text = "ab ab. ba ab. ab" in
+
+  assert (encoding_chunk enc text { max_tokens = 5; overlap = 0; boundary = Any } (Tokens []) All
+          = Ok [(0, 7, [|2; 3; 2; 4; 3|]); (7, 13, [|1; 0; 3; 2; 4|]); (13, 16, [|3; 2|])]);
+  (* Consecutive chunks share at most two tokens *)
+  assert (encoding_chunk enc text { max_tokens = 5; overlap = 2; boundary = Any } (Tokens []) All
+          = Ok [(0, 7, [|2; 3; 2; 4; 3|]); (5, 10, [|4; 3; 1; 0; 3|]); (9, 16, [|3; 2; 4; 3; 2|])]);
+  (* The first chunk ends after the sentence instead of after the following space *)
+  (match encoding_chunk enc text { max_tokens = 5; overlap = 0; boundary = Sentence } (Tokens []) All with
+   | Ok ((0, 6, [|2; 3; 2; 4|]) :: _) -> ()
+   | _ -> assert false);
+  assert (encoding_chunk enc "" { max_tokens = 5; overlap = 0; boundary = Any } (Tokens []) All = Ok []);
+
+  (* Special tokens *)
+  let text = "ab<|endoftext|>ab" in
+  assert (encoding_chunk enc text { max_tokens = 2; overlap = 0; boundary = Any } All All
+          = Ok [(0, 15, [|2; 6|]); (15, 17, [|2|])]);
+  assert (encoding_chunk enc text { max_tokens = 2; overlap = 0; boundary = Any } (Tokens []) All
+          = Error (Disallowed_special_token "<|endoftext|>"));
+  assert (encoding_chunk enc "ab" { max_tokens = 2; overlap = 2; boundary = Any } (Tokens []) All
+          = Error (Bad_argument "chunk overlap 2 must be smaller than max_tokens 2"));
+  Printf.printf "Chunking passed\n"
+
+let test_decode_with_offsets () =
+  let open Ocaml_rust_tiktok in
+  (* "a", "b", "ab", " ", and the two bytes of "\xc3\xa9" (e acute) as separate tokens *)
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\nIA== 3\nww== 4\nqQ== 5\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [("<|endoftext|>", 6)] "\\w+| ") in
+
+  (* Token 5 starts in the middle of the character begun by token 4 *)
+  assert (encoding_decode_with_offsets enc [|2; 3; 4; 5; 0|]
+          = Ok ("ab \xc3\xa9a", [|0; 2; 3; 3; 4|], [|0; 2; 3; 3; 5|]));
+  assert (encoding_decode_with_offsets enc [|2; 6; 0|] = Ok ("ab<|endoftext|>a", [|0; 2; 15|], [|0; 2; 15|]));
+  assert (encoding_decode_with_offsets enc [||] = Ok ("", [||], [||]));
+  assert (encoding_decode_with_offsets enc [|2; 4|] = Error (Invalid_utf8 2));
+  assert (encoding_decode_with_offsets enc [|2; 99|] = Error (Unknown_token_at (99, 1)));
+  Printf.printf "Decode with offsets passed\n"
+
+let test_encode_with_spans () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+
+  assert (encoding_encode_with_spans enc "ab ba<|endoftext|>" ["<|endoftext|>"]
+          = Ok ([|2; 3; 1; 0; 4|], [|(0, 2); (2, 3); (3, 4); (4, 5); (5, 18)|]));
+  (* Spans within a piece follow the merges *)
+  assert (encoding_encode_with_spans enc "aab" [] = Ok ([|0; 2|], [|(0, 1); (1, 3)|]));
+  assert (encoding_encode_with_spans enc "" [] = Ok ([||], [||]));
+  Printf.printf "Encode with spans passed\n"
+
+let test_decode_errors () =
+  let open Ocaml_rust_tiktok in
+  (* "a", "b", "ab", and the two bytes of "\xc3\xa9" (e acute) as separate tokens *)
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\nww== 3\nqQ== 4\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [] "\\w+") in
+
+  List.iter (fun errors -> assert (encoding_decode enc [|2; 3; 4|] errors = Ok "ab\xc3\xa9"))
+    [Strict; Replace; Ignore];
+  (* A lone lead byte *)
+  assert (encoding_decode enc [|2; 3; 0|] Strict = Error (Invalid_utf8 2));
+  assert (encoding_decode enc [|2; 3; 0|] Replace = Ok "ab\xef\xbf\xbda");
+  assert (encoding_decode enc [|2; 3; 0|] Ignore = Ok "aba");
+  assert (encoding_decode enc [|4; 4|] Replace = Ok "\xef\xbf\xbd\xef\xbf\xbd");
+  assert (encoding_decode enc [|2; 99|] Replace = Error (Unknown_token_at (99, 1)));
+  Printf.printf "Decode error modes passed\n"
+
+let test_stream_encoder () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  let stream = Result.get_ok (encoding_stream_encoder enc ["<|endoftext|>"]) in
+
+  (* The last two pieces, and a possible start of a special token, wait for more input *)
+  let pushed =
+    List.map (fun chunk -> Result.get_ok (stream_encoder_push stream chunk))
+      ["a"; "b"; " a"; "<|endo"; "ftext|>b"]
+  in
+  assert (pushed = [[||]; [||]; [|2|]; [||]; [|3; 0; 4|]]);
+  assert (stream_encoder_finish stream = Ok [|1|]);
+  assert (Array.concat pushed @ [|1|] = Result.get_ok (encoding_encode enc "ab a<|endoftext|>b" ["<|endoftext|>"]));
+  (* The encoder is reset by finish *)
+  assert (stream_encoder_push stream "ab" = Ok [||]);
+  assert (stream_encoder_finish stream = Ok [|2|]);
+
+  let stream = Result.get_ok (encoding_stream_encoder enc []) in
+  assert (stream_encoder_push stream "a\xff" = Error (Invalid_utf8 1));
+  let stream = Result.get_ok (encoding_stream_encoder enc []) in
+  (* An incomplete UTF-8 sequence is kept until the next chunk *)
+  assert (stream_encoder_push stream "a\xc3" = Ok [||]);
+  assert (stream_encoder_finish stream = Error (Invalid_utf8 1));
+  (* A failed finish also resets the encoder *)
+  assert (stream_encoder_push stream "ab" = Ok [||]);
+  assert (stream_encoder_finish stream = Ok [|2|]);
+
+  (* More text can change the piece before the last one: with a contraction rule, "we'r" splits
+     into "we", "'", "r" but "we're" into "we", "'re". The "'r" and "n'" tokens only come out of a
+     one-shot encode if the stream does not emit "'" or " don" too early. *)
+  let bytes = List.init 256 (fun b -> (Bytes.make 1 (Char.chr b), b)) in
+  let encoder = bytes @ [(Bytes.of_string "'r", 256); (Bytes.of_string "n'", 257)] in
+  List.iter
+    (fun (name, chunks) ->
+      let pattern = Option.get (encoding_pattern name) in
+      let enc = Result.get_ok (encoding_new encoder [] pattern) in
+      let stream = Result.get_ok (encoding_stream_encoder enc []) in
+      let pushed = List.map (fun chunk -> Result.get_ok (stream_encoder_push stream chunk)) chunks in
+      let tokens = Array.concat (pushed @ [Result.get_ok (stream_encoder_finish stream)]) in
+      assert (tokens = Result.get_ok (encoding_encode_ordinary enc (String.concat "" chunks))))
+    [("r50k_base", ["we'r"; "e"]); ("o200k_base", ["I don'"; "t"])];
+  Printf.printf "Stream encoder passed\n"
+
+let test_stream_decoder () =
+  let open Ocaml_rust_tiktok in
+  (* "a", and the two bytes of "\xc3\xa9" (e acute) as separate tokens *)
+  let contents = "YQ== 0\nww== 1\nqQ== 2\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [("<|endoftext|>", 3)] "\\w+") in
+  let stream = encoding_stream_decoder enc Strict in
+
+  (* The first byte of "\xc3\xa9" is held back until the second arrives *)
+  assert (stream_decoder_push stream [|0; 1|] = Ok [Text "a"]);
+  assert (stream_decoder_push stream [|2; 3; 0|]
+          = Ok [Text "\xc3\xa9"; Special_token "<|endoftext|>"; Text "a"]);
+  assert (stream_decoder_push stream [|1|] = Ok []);
+  assert (stream_decoder_finish stream = Error (Invalid_utf8 17));
+  assert (stream_decoder_push stream [|0; 99|] = Error (Unknown_token_at (99, 1)));
+
+  (* A special token ends an incomplete sequence *)
+  let stream = encoding_stream_decoder enc Replace in
+  assert (stream_decoder_push stream [|1; 3|] = Ok [Text "\xef\xbf\xbd"; Special_token "<|endoftext|>"]);
+  assert (stream_decoder_push stream [|1|] = Ok []);
+  assert (stream_decoder_finish stream = Ok [Text "\xef\xbf\xbd"]);
+
+  (* A failed push leaves the stream as it was: neither the held back byte nor the text and
+     special token before the unknown token are consumed *)
+  let stream = encoding_stream_decoder enc Strict in
+  assert (stream_decoder_push stream [|0; 1|] = Ok [Text "a"]);
+  assert (stream_decoder_push stream [|2; 0; 3; 99|] = Error (Unknown_token_at (99, 3)));
+  assert (stream_decoder_push stream [|1; 2|] = Error (Invalid_utf8 1));
+  assert (stream_decoder_push stream [|2; 3|] = Ok [Text "\xc3\xa9"; Special_token "<|endoftext|>"]);
+  assert (stream_decoder_finish stream = Ok []);
+  Printf.printf "Stream decoder passed\n"
+
+let test_train_bpe () =
+  let open Ocaml_rust_tiktok in
+  let pattern = "\\w+| " in
+  (* "ab" occurs four times, then "abc" once *)
+  let ranks = Result.get_ok (train_bpe ["ab ab ab abc"] pattern 258) in
+  assert (List.length ranks = 258);
+  assert (List.assoc (Bytes.of_string "ab") ranks = 256);
+  assert (List.assoc (Bytes.of_string "abc") ranks = 257);
+  let enc = Result.get_ok (encoding_new ranks [] pattern) in
+  assert (encoding_encode_ordinary enc "abc ab" = Ok [|257; 32; 256|]);
+
+  let path = Filename.temp_file "trained" ".tiktoken" in
+  assert (write_tiktoken_file ranks path = Ok ());
+  let loaded = Result.get_ok (encoding_new_from_file path [] pattern) in
+  assert (encoding_encode_ordinary loaded "abc ab" = Ok [|257; 32; 256|]);
+  Sys.remove path;
+
+  (* Training stops early once every piece is a single token *)
+  assert (Result.map List.length (train_bpe ["ab"] pattern 1000) = Ok 257);
+  assert (train_bpe [] pattern 255 = Error (Bad_argument "vocabulary size 255 must be at least 256"));
+  assert (encoding_pattern "r50k_base" <> None);
+  Printf.printf "BPE training passed\n"
+
+let test_encoding_extend () =
+  let open Ocaml_rust_tiktok in
+  let contents = "YQ== 0\nYg== 1\nYWI= 2\n" in
+  let enc = Result.get_ok (encoding_new_from_string contents [("<|endoftext|>", 3)] "\\w+") in
+
+  let chat = Result.get_ok (encoding_extend enc [("<|im_start|>", 4)] [] None) in
+  assert (encoding_encode chat "ab<|im_start|><|endoftext|>" ["<|im_start|>"; "<|endoftext|>"] = Ok [|2; 4; 3|]);
+  assert (encoding_decode_bytes enc [|4|] = Error (Unknown_token_at (4, 0)));
+  let plain = Result.get_ok (encoding_extend enc [] ["<|endoftext|>"] None) in
+  assert (encoding_decode_bytes plain [|3|] = Error (Unknown_token_at (3, 0)));
+  let bytes = Result.get_ok (encoding_extend enc [] [] (Some "a|b")) in
+  assert (encoding_encode_ordinary bytes "ab" = Ok [|0; 1|]);
+  assert (encoding_encode_ordinary enc "ab" = Ok [|2|]);
+
+  assert (encoding_extend enc [("<|x|>", 2)] [] None = Error (Duplicate_rank 2));
+  assert (encoding_extend enc [("<|x|>", 3)] [] None = Error (Duplicate_rank 3));
+  assert (encoding_extend enc [] ["<|x|>"] None = Error (Bad_argument "no special token \"<|x|>\" to remove"));
+  Printf.printf "Encoding extension passed\n"
+
+let test_snapshot () =
+  let open Ocaml_rust_tiktok in
+  let enc = small_encoding () in
+  let path = Filename.temp_file "encoding" ".snapshot" in
+  assert (encoding_save_snapshot enc path = Ok ());
+  let loaded = Result.get_ok (encoding_load_snapshot path) in
+  assert (encoding_encode loaded "ab ba<|endoftext|>" ["<|endoftext|>"] = Ok [|2; 3; 1; 0; 4|]);
+  assert (encoding_decode_bytes loaded [|2; 4|] = Ok (Bytes.of_string "ab<|endoftext|>"));
+  assert (encoding_token_byte_values loaded = encoding_token_byte_values enc);
+
+  (* Flip a bit in the middle of the file *)
+  let write data =
+    let oc = open_out_bin path in
+    output_string oc data;
+    close_out oc
+  in
+  let ic = open_in_bin path in
+  let data = Bytes.of_string (really_input_string ic (in_channel_length ic)) in
+  close_in ic;
+  Bytes.set data 20 (Char.chr (Char.code (Bytes.get data 20) lxor 1));
+  write (Bytes.to_string data);
+  assert (encoding_load_snapshot path = Error (Invalid_snapshot "checksum mismatch"));
+  write small_contents;
+  assert (encoding_load_snapshot path = Error (Invalid_snapshot "not a snapshot"));
+  Sys.remove path;
+  assert (Result.is_error (encoding_load_snapshot path));
+  Printf.printf "Snapshots passed\n"
+
+(* Run the tests *)
+let () =
+  test_core_bpe_new ();
+  test_core_bpe_new_from_string ();
+  test_list_encoding_names ();
+  test_encoding_name_for_model ();
+  test_core_bpe_encode_decode ();
+  test_encoding_handle ();
+  test_core_bpe_lifecycle ();
+  test_errors ();
+  test_decode_unknown_tokens ();
+  test_disallowed_special ();
+  test_long_piece ();
+  test_batch ();
+  test_release_runtime_lock ();
+  test_bigarray ();
+  test_count_tokens ();
+  test_truncate ();
+  test_chunk ();
+  test_decode_with_offsets ();
+  test_encode_with_spans ();
+  test_decode_errors ();
+  test_stream_encoder ();
+  test_stream_decoder ();
+  test_train_bpe ();
+  test_encoding_extend ();
+  test_snapshot ()
  